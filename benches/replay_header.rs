@@ -0,0 +1,51 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lapin::types::{AMQPValue, FieldTable, ShortString};
+use rabbit_revival::replay::{header_path, lookup_header, lookup_header_path};
+
+const MESSAGE_COUNT: usize = 100_000;
+const HEADER_NAME: &str = "x-stream-transaction-id";
+
+//headers as replay_header sees them on a stream, one transaction id per message
+fn headers() -> Vec<FieldTable> {
+    (0..MESSAGE_COUNT)
+        .map(|i| {
+            let mut headers = FieldTable::default();
+            headers.insert(
+                ShortString::from("x-stream-offset"),
+                AMQPValue::LongLongInt(i as i64),
+            );
+            headers.insert(
+                ShortString::from(HEADER_NAME),
+                AMQPValue::LongString(format!("transaction_{}", i).into()),
+            );
+            headers
+        })
+        .collect()
+}
+
+fn replay_header_lookup(c: &mut Criterion) {
+    let headers = headers();
+    let mut group = c.benchmark_group("replay_header_lookup");
+
+    group.bench_function("parse name per message", |b| {
+        b.iter(|| {
+            for headers in &headers {
+                black_box(lookup_header(headers, black_box(HEADER_NAME)));
+            }
+        })
+    });
+
+    group.bench_function("pre-computed path", |b| {
+        b.iter(|| {
+            let target_path = header_path(black_box(HEADER_NAME));
+            for headers in &headers {
+                black_box(lookup_header_path(headers, &target_path));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, replay_header_lookup);
+criterion_main!(benches);
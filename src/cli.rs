@@ -0,0 +1,470 @@
+//one-shot fetches and replays from the command line, configured by the same environment
+//variables as the server. The messages are printed to stdout as JSON, logs go to stderr.
+//
+//    rabbit-revival fetch --queue replay --from -1h --transaction-id transaction_499
+//    rabbit-revival replay-header --queue replay --header x-stream-transaction-id=transaction_499
+
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
+use rabbit_revival::{
+    initialize_state, replay::fetch_messages, replay_once, resolve_time, AMQPHeader, Cluster,
+    HeaderMatch, HeaderReplay, MatchType, MessageQuery, PublishVia, ReplayMode, ReplayOrder,
+    TimeFrameReplay,
+};
+
+//at least one message was printed
+pub const EXIT_MATCHED: i32 = 0;
+//nothing matched, an empty list was printed
+pub const EXIT_NO_MATCH: i32 = 1;
+//the command failed and printed the error to stderr, clap exits with the same code on invalid
+//arguments
+pub const EXIT_ERROR: i32 = 2;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Fetches and replays the messages of RabbitMQ streams, runs the HTTP server without a subcommand"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    #[command(about = "Lists the messages of a stream like GET /list")]
+    Fetch(FetchArgs),
+    #[command(about = "Replays the messages between --from and --to like a time_frame replay")]
+    ReplayTimeframe(TimeFrameArgs),
+    #[command(about = "Replays the messages carrying the headers like a header replay")]
+    ReplayHeader(HeaderArgs),
+}
+
+//the parameters of `GET /list`
+#[derive(Args, Debug)]
+pub struct FetchArgs {
+    #[arg(long)]
+    pub queue: String,
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    pub from: Option<DateTime<Utc>>,
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    pub to: Option<DateTime<Utc>>,
+    #[arg(long)]
+    pub transaction_id: Option<String>,
+    #[arg(long)]
+    pub app_id: Option<String>,
+    #[arg(long)]
+    pub correlation_id: Option<String>,
+    #[arg(long)]
+    pub correlation_id_prefix: bool,
+    #[arg(long)]
+    pub header_absent: Option<String>,
+    #[arg(long)]
+    pub dead_letter_reason: Option<String>,
+    #[arg(long)]
+    pub dead_letter_min_count: Option<u64>,
+    #[arg(long)]
+    pub dead_letter_queue: Option<String>,
+    #[arg(long)]
+    pub min_priority: Option<u16>,
+    #[arg(long)]
+    pub max_priority: Option<u16>,
+    #[arg(long)]
+    pub min_bytes: Option<usize>,
+    #[arg(long)]
+    pub max_bytes: Option<usize>,
+    #[arg(long)]
+    pub body_contains: Option<String>,
+    #[arg(long)]
+    pub body_json_pointer: Option<String>,
+    #[arg(long, allow_hyphen_values = true)]
+    pub body_json_eq: Option<String>,
+    #[arg(long)]
+    pub base64: bool,
+}
+
+impl From<FetchArgs> for MessageQuery {
+    fn from(args: FetchArgs) -> Self {
+        MessageQuery {
+            from: args.from,
+            to: args.to,
+            transaction_id: args.transaction_id,
+            app_id: args.app_id,
+            correlation_id: args.correlation_id,
+            correlation_id_prefix: args.correlation_id_prefix.then_some(true),
+            header_absent: args.header_absent,
+            dead_letter_reason: args.dead_letter_reason,
+            dead_letter_min_count: args.dead_letter_min_count,
+            dead_letter_queue: args.dead_letter_queue,
+            min_priority: args.min_priority,
+            max_priority: args.max_priority,
+            min_bytes: args.min_bytes,
+            max_bytes: args.max_bytes,
+            body_contains: args.body_contains,
+            body_json_pointer: args.body_json_pointer,
+            body_json_eq: args.body_json_eq,
+            base64: args.base64.then_some(true),
+            ..MessageQuery::new(args.queue)
+        }
+    }
+}
+
+//the fields time frame and header replays share
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    #[arg(long)]
+    pub app_id: Option<String>,
+    #[arg(long)]
+    pub skip_replayed: Option<bool>,
+    #[arg(long)]
+    pub max_messages: Option<u64>,
+    #[arg(long)]
+    pub transaction_value: Option<String>,
+    #[arg(long, value_parser = parse_name::<Cluster>)]
+    pub target: Option<Cluster>,
+    #[arg(long)]
+    pub rate_limit: Option<u32>,
+    #[arg(long, value_parser = parse_name::<ReplayOrder>)]
+    pub order_by: Option<ReplayOrder>,
+    #[arg(long, value_parser = parse_name::<PublishVia>)]
+    pub publish_via: Option<PublishVia>,
+}
+
+#[derive(Args, Debug)]
+pub struct TimeFrameArgs {
+    #[arg(long)]
+    pub queue: String,
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    pub from: DateTime<Utc>,
+    #[arg(long, value_parser = parse_time, allow_hyphen_values = true)]
+    pub to: DateTime<Utc>,
+    #[arg(long)]
+    pub header_absent: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    pub exclude_offsets: Vec<u64>,
+    #[arg(long)]
+    pub max_scan_messages: Option<u64>,
+    #[command(flatten)]
+    pub replay: ReplayArgs,
+}
+
+impl From<TimeFrameArgs> for TimeFrameReplay {
+    fn from(args: TimeFrameArgs) -> Self {
+        let ReplayArgs {
+            app_id,
+            skip_replayed,
+            max_messages,
+            transaction_value,
+            target,
+            rate_limit,
+            order_by,
+            publish_via,
+        } = args.replay;
+        TimeFrameReplay {
+            header_absent: args.header_absent,
+            exclude_offsets: (!args.exclude_offsets.is_empty()).then_some(args.exclude_offsets),
+            max_scan_messages: args.max_scan_messages,
+            app_id,
+            skip_replayed,
+            max_messages,
+            transaction_value,
+            target,
+            rate_limit,
+            order_by,
+            publish_via,
+            ..TimeFrameReplay::new(args.queue, args.from, args.to)
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct HeaderArgs {
+    #[arg(long)]
+    pub queue: String,
+    //`name=value`, given once per condition
+    #[arg(long = "header", value_parser = parse_header, required = true)]
+    pub headers: Vec<AMQPHeader>,
+    #[arg(long = "match", value_parser = parse_name::<HeaderMatch>)]
+    pub header_match: Option<HeaderMatch>,
+    //applies to every condition
+    #[arg(long, value_parser = parse_name::<MatchType>)]
+    pub match_type: Option<MatchType>,
+    #[command(flatten)]
+    pub replay: ReplayArgs,
+}
+
+impl From<HeaderArgs> for HeaderReplay {
+    fn from(args: HeaderArgs) -> Self {
+        let ReplayArgs {
+            app_id,
+            skip_replayed,
+            max_messages,
+            transaction_value,
+            target,
+            rate_limit,
+            order_by,
+            publish_via,
+        } = args.replay;
+        let match_type = args.match_type.unwrap_or_default();
+        let headers = args
+            .headers
+            .into_iter()
+            .map(|header| AMQPHeader {
+                match_type,
+                ..header
+            })
+            .collect();
+        HeaderReplay {
+            header: None,
+            headers: Some(headers),
+            header_match: args.header_match,
+            app_id,
+            skip_replayed,
+            max_messages,
+            transaction_value,
+            target,
+            rate_limit,
+            order_by,
+            publish_via,
+            ..HeaderReplay::new(args.queue, "", "")
+        }
+    }
+}
+
+//the same absolute and relative times the API accepts
+fn parse_time(expression: &str) -> Result<DateTime<Utc>, String> {
+    resolve_time(expression, Utc::now())
+}
+
+//enums are named as in a request body, e.g. `secondary` or `default_exchange`
+fn parse_name<T: serde::de::DeserializeOwned>(name: &str) -> Result<T, String> {
+    serde_json::from_value(serde_json::Value::String(name.to_string()))
+        .map_err(|err| err.to_string())
+}
+
+fn parse_header(condition: &str) -> Result<AMQPHeader, String> {
+    match condition.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok(AMQPHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+            match_type: MatchType::Exact,
+        }),
+        _ => Err(format!("expected name=value, got {:?}", condition)),
+    }
+}
+
+pub async fn run(command: Command) -> i32 {
+    match execute(command).await {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            EXIT_ERROR
+        }
+    }
+}
+
+async fn execute(command: Command) -> anyhow::Result<i32> {
+    let state = initialize_state().await?;
+    let messages = match command {
+        Command::Fetch(args) => {
+            let message_query = MessageQuery::from(args);
+            state.queue_access().check(&message_query.queue)?;
+            fetch_messages(
+                &state.pool(),
+                &state.amqp_config(),
+                state.message_options(),
+                message_query,
+            )
+            .await?
+        }
+        Command::ReplayTimeframe(args) => {
+            replay_once(&state, ReplayMode::TimeFrameReplay(args.into())).await?
+        }
+        Command::ReplayHeader(args) => {
+            replay_once(&state, ReplayMode::HeaderReplay(args.into())).await?
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&messages)?);
+    Ok(if messages.is_empty() {
+        EXIT_NO_MATCH
+    } else {
+        EXIT_MATCHED
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use clap::Parser;
+
+    fn parse(args: &[&str]) -> Result<Option<super::Command>, clap::Error> {
+        super::Cli::try_parse_from(std::iter::once("rabbit-revival").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    }
+
+    #[test]
+    fn test_fetch_args() {
+        assert!(parse(&[]).unwrap().is_none());
+
+        let command = parse(&[
+            "fetch",
+            "--queue",
+            "replay",
+            "--from",
+            "2023-10-06T14:00:00Z",
+            "--to",
+            "-1h",
+            "--transaction-id",
+            "transaction_1",
+            "--body-json-pointer",
+            "/order/total",
+            "--body-json-eq",
+            "-42",
+            "--base64",
+        ])
+        .unwrap();
+        let Some(super::Command::Fetch(args)) = command else {
+            panic!("{:?}", command);
+        };
+        let message_query = rabbit_revival::MessageQuery::from(args);
+        assert_eq!(message_query.queue, "replay");
+        assert_eq!(
+            message_query.from,
+            Some(chrono::Utc.with_ymd_and_hms(2023, 10, 6, 14, 0, 0).unwrap())
+        );
+        assert!(message_query.to.unwrap() < chrono::Utc::now() - chrono::Duration::minutes(59));
+        assert_eq!(
+            message_query.transaction_id.as_deref(),
+            Some("transaction_1")
+        );
+        assert_eq!(message_query.body_json_eq.as_deref(), Some("-42"));
+        assert_eq!(message_query.base64, Some(true));
+        assert_eq!(message_query.correlation_id_prefix, None);
+
+        let tests = vec![
+            vec!["fetch"],
+            vec!["fetch", "--queue", "replay", "--from", "yesterday"],
+            vec!["fetch", "--queue", "replay", "--min-bytes", "1KB"],
+            vec!["fetch", "--queue", "replay", "--offset", "42"],
+        ];
+        for args in tests {
+            assert!(parse(&args).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_replay_args() {
+        let command = parse(&[
+            "replay-timeframe",
+            "--queue",
+            "replay",
+            "--from",
+            "-2h",
+            "--to",
+            "now",
+            "--exclude-offsets",
+            "3,7",
+            "--target",
+            "secondary",
+            "--rate-limit",
+            "100",
+            "--publish-via",
+            "default_exchange",
+        ])
+        .unwrap();
+        let Some(super::Command::ReplayTimeframe(args)) = command else {
+            panic!("{:?}", command);
+        };
+        let time_frame = rabbit_revival::TimeFrameReplay::from(args);
+        assert_eq!(time_frame.queue, "replay");
+        assert_eq!(time_frame.to - time_frame.from, chrono::Duration::hours(2));
+        assert_eq!(time_frame.exclude_offsets, Some(vec![3, 7]));
+        assert_eq!(time_frame.target, Some(rabbit_revival::Cluster::Secondary));
+        assert_eq!(time_frame.rate_limit, Some(100));
+        assert_eq!(
+            time_frame.publish_via,
+            Some(rabbit_revival::PublishVia::DefaultExchange)
+        );
+        assert_eq!(time_frame.max_messages, None);
+
+        let command = parse(&[
+            "replay-header",
+            "--queue",
+            "replay",
+            "--header",
+            "x-stream-transaction-id=transaction_1",
+            "--header",
+            "tenant-id=acme=eu",
+            "--match",
+            "any",
+            "--match-type",
+            "prefix",
+            "--skip-replayed",
+            "true",
+        ])
+        .unwrap();
+        let Some(super::Command::ReplayHeader(args)) = command else {
+            panic!("{:?}", command);
+        };
+        let header = rabbit_revival::HeaderReplay::from(args);
+        let conditions: Vec<(&str, &str, rabbit_revival::MatchType)> = header
+            .conditions()
+            .map(|condition| {
+                (
+                    condition.name.as_str(),
+                    condition.value.as_str(),
+                    condition.match_type,
+                )
+            })
+            .collect();
+        assert_eq!(
+            conditions,
+            vec![
+                (
+                    "x-stream-transaction-id",
+                    "transaction_1",
+                    rabbit_revival::MatchType::Prefix
+                ),
+                ("tenant-id", "acme=eu", rabbit_revival::MatchType::Prefix),
+            ]
+        );
+        assert_eq!(header.header_match, Some(rabbit_revival::HeaderMatch::Any));
+        assert_eq!(header.skip_replayed, Some(true));
+
+        let tests = vec![
+            vec!["replay-header", "--queue", "replay"],
+            vec![
+                "replay-header",
+                "--queue",
+                "replay",
+                "--header",
+                "tenant-id",
+            ],
+            vec!["replay-header", "--queue", "replay", "--header", "=acme"],
+            vec![
+                "replay-header",
+                "--queue",
+                "replay",
+                "--header",
+                "tenant-id=acme",
+                "--match",
+                "either",
+            ],
+            vec!["replay-timeframe", "--queue", "replay", "--from", "-2h"],
+            vec![
+                "replay-timeframe",
+                "--queue",
+                "replay",
+                "--from",
+                "-2h",
+                "--to",
+                "now",
+                "--target",
+                "tertiary",
+            ],
+        ];
+        for args in tests {
+            assert!(parse(&args).is_err(), "{:?}", args);
+        }
+    }
+}
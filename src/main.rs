@@ -1,31 +1,58 @@
-use std::{net::SocketAddr, time::Instant};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 
 use axum::{
     extract::MatchedPath,
-    http::Request,
+    http::{HeaderName, HeaderValue, Request},
     middleware::{self, Next},
     response::IntoResponse,
     routing::{get, post},
     Router,
 };
+use clap::Parser;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
-use rabbit_revival::{get_messages, health, initialize_state, replay};
+use rabbit_revival::{
+    get_consumer_offsets, get_duplicates, get_last_replay, get_message_count, get_messages,
+    get_queues, get_redacted_messages, get_replay_job, get_stream_offsets, health, health_detailed,
+    initialize_state, peek, reload_credentials, replay, replay_preview, replay_to_file,
+    require_api_token, AppState,
+};
 use sysinfo::{CpuExt, System, SystemExt};
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt};
+use tower_http::{set_header::SetResponseHeaderLayer, trace::TraceLayer};
+use tracing_subscriber::{
+    fmt::writer::BoxMakeWriter, prelude::__tracing_subscriber_SubscriberExt,
+    util::SubscriberInitExt,
+};
+
+mod cli;
+
+//sent with every response so clients can check which version of the API they talk to
+static VERSION_HEADER_NAME: HeaderName = HeaderName::from_static("x-rabbit-revival-version");
+static VERSION_HEADER_VALUE: HeaderValue = HeaderValue::from_static(env!("CARGO_PKG_VERSION"));
 
 #[tokio::main]
 async fn main() {
-    // initialize tracing
+    let cli = cli::Cli::parse();
+
+    // initialize tracing, a command logs to stderr to keep its JSON on stdout alone
+    let (default_filter, writer) = match cli.command {
+        Some(_) => ("rabbit_revival=info", BoxMakeWriter::new(std::io::stderr)),
+        None => (
+            "rabbit_revival=debug,tower_http=trace,axum::rejection=trace",
+            BoxMakeWriter::new(std::io::stdout),
+        ),
+    };
     tracing_subscriber::registry()
         .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                "rabbit_revival=debug,tower_http=trace,axum::rejection=trace".into()
-            }),
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| default_filter.into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer))
         .init();
 
+    if let Some(command) = cli.command {
+        std::process::exit(cli::run(command).await);
+    }
+
     let enable_metrics = std::env::var("ENABLE_METRICS").unwrap_or("false".to_string());
 
     if enable_metrics == "true" {
@@ -62,15 +89,64 @@ fn setup_metrics_recorder() -> PrometheusHandle {
 }
 
 async fn main_app() -> Router {
+    let state = match initialize_state().await {
+        Ok(state) => state,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    tokio::spawn(reload_credentials_on_sighup(state.clone()));
     Router::new()
         .route("/list", get(get_messages))
+        .route("/messages/count", get(get_message_count))
+        .route("/messages/duplicates", get(get_duplicates))
+        .route("/messages/redacted", post(get_redacted_messages))
         .route("/replay", post(replay))
+        .route("/replay/preview", post(replay_preview))
+        .route("/replay/:job_id", get(get_replay_job))
         .route("/health", get(health))
+        .route("/health/detailed", get(health_detailed))
+        .route("/streams/:queue/offsets", get(get_stream_offsets))
+        .route("/queues", get(get_queues))
+        .route("/queues/:queue/peek", get(peek))
+        .route("/queues/:queue/consumer-offsets", get(get_consumer_offsets))
+        .route("/queues/:queue/replay-to-file", post(replay_to_file))
+        .route("/queues/:queue/last-replay", get(get_last_replay))
+        .route("/admin/reload-credentials", post(reload_credentials))
+        .layer(middleware::from_fn_with_state(
+            state.api_auth(),
+            require_api_token,
+        ))
         .layer(TraceLayer::new_for_http())
-        .with_state(initialize_state().await)
+        .layer(SetResponseHeaderLayer::overriding(
+            VERSION_HEADER_NAME.clone(),
+            VERSION_HEADER_VALUE.clone(),
+        ))
+        .with_state(state)
         .route_layer(middleware::from_fn(track_metrics))
 }
 
+//rotated credentials can be picked up with `kill -HUP` as well as through the admin endpoint
+#[cfg(unix)]
+async fn reload_credentials_on_sighup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            tracing::error!("could not listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(err) = state.reload_credentials().await {
+            tracing::error!("reloading the credentials failed: {}", err);
+        }
+    }
+}
+
 async fn start_main_server() {
     let app = main_app().await;
 
@@ -0,0 +1,413 @@
+//calls to the RabbitMQ management HTTP API. AMQP does not provide a way to list queues or get
+//meta data about them, thus the management API is used.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{RabbitmqApiConfig, ReplayError};
+
+//the management API pages its queue list, at most this many queues are requested per page
+const QUEUE_PAGE_SIZE: u64 = 100;
+
+//upper bound for the pause before a single retry, however many attempts are configured
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+//how often a management API call is attempted and how long the first retry waits, every
+//further retry waits twice as long
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub const DEFAULT: Self = Self {
+        attempts: 3,
+        base_delay: Duration::from_millis(100),
+    };
+
+    //the pause before retry number `retry`, starting at 1. `jitter` between 0 and 1 takes up to
+    //half of it off, so callers that failed together don't retry together
+    fn delay(&self, retry: u32, jitter: f64) -> Duration {
+        let exponential = 2u32
+            .checked_pow(retry.saturating_sub(1))
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+        exponential.mul_f64(1.0 - jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+//a random number between 0 and 1, a fresh `RandomState` is seeded differently every time
+fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+//a management plugin that restarts or a load balancer without a healthy backend is worth
+//another attempt, a refused login or a missing queue won't change
+fn is_retryable(res: &reqwest::Result<reqwest::Response>) -> bool {
+    match res {
+        Ok(res) => res.status().is_server_error(),
+        Err(err) => err.is_connect() || err.is_timeout(),
+    }
+}
+
+//a queue as listed by the management API. `messages` is missing until the first statistics of
+//the queue were collected
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueueInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub queue_type: Option<String>,
+    pub messages: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QueuePage {
+    items: Vec<QueueInfo>,
+    page_count: u64,
+}
+
+//GET of a management API url with the configured credentials, retried as told by the
+//`RetryPolicy` of the config. The response of the last attempt is returned, server errors
+//included
+pub async fn get(rabitmq_api_config: &RabbitmqApiConfig, url: String) -> Result<reqwest::Response> {
+    let retry = rabitmq_api_config.retry;
+    let mut attempt = 1;
+    let res = loop {
+        let res = rabitmq_api_config
+            .client
+            .get(&url)
+            .basic_auth(
+                rabitmq_api_config.username.clone(),
+                Some(rabitmq_api_config.password.clone()),
+            )
+            .send()
+            .await;
+        if attempt >= retry.attempts || !is_retryable(&res) {
+            break res?;
+        }
+        let delay = retry.delay(attempt, jitter());
+        let reason = match &res {
+            Ok(res) => res.status().to_string(),
+            Err(err) => err.to_string(),
+        };
+        tracing::warn!(
+            "management API call {} failed on attempt {} of {}, retrying in {:?}: {}",
+            url,
+            attempt,
+            retry.attempts,
+            delay,
+            reason
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    };
+    check_caller_credentials(rabitmq_api_config, res)
+}
+
+//credentials forwarded from the request that the management API refuses are the caller's
+//to fix, the response of refused service credentials is handled like any other
+fn check_caller_credentials(
+    rabitmq_api_config: &RabbitmqApiConfig,
+    res: reqwest::Response,
+) -> Result<reqwest::Response> {
+    let refused = matches!(
+        res.status(),
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+    );
+    if rabitmq_api_config.caller_credentials && refused {
+        return Err(ReplayError::CallerCredentialsRejected.into());
+    }
+    Ok(res)
+}
+
+pub async fn get_queue_details(
+    rabitmq_api_config: &RabbitmqApiConfig,
+    name: &str,
+) -> Result<serde_json::Value> {
+    let url = rabitmq_api_config.resource_url("queues", name);
+
+    let res = get(rabitmq_api_config, url).await?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(ReplayError::QueueNotFound(name.to_string()).into());
+    }
+    let res = res.error_for_status()?.json::<serde_json::Value>().await?;
+
+    if let Some(res) = res.get("type") {
+        if res != "stream" {
+            return Err(ReplayError::QueueNotAStream(name.to_string()).into());
+        }
+    }
+
+    Ok(res)
+}
+
+pub async fn get_queue_message_count(
+    rabitmq_api_config: &RabbitmqApiConfig,
+    name: &str,
+) -> Result<Option<u64>> {
+    let res = get_queue_details(rabitmq_api_config, name).await?;
+
+    let message_count = res.get("messages");
+
+    match message_count {
+        Some(message_count) => message_count.as_u64().map(Some).ok_or_else(|| {
+            anyhow!(
+                "Management API reported {} messages for queue {}",
+                message_count,
+                name
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+pub async fn get_exchange_type(
+    rabitmq_api_config: &RabbitmqApiConfig,
+    name: &str,
+) -> Result<Option<String>> {
+    let url = rabitmq_api_config.resource_url("exchanges", name);
+
+    let res = get(rabitmq_api_config, url)
+        .await?
+        .json::<serde_json::Value>()
+        .await?;
+
+    Ok(res
+        .get("type")
+        .and_then(|exchange_type| exchange_type.as_str())
+        .map(str::to_string))
+}
+
+//every queue of the configured vhost, whatever its type, fetched page by page
+pub async fn list_queues(rabitmq_api_config: &RabbitmqApiConfig) -> Result<Vec<QueueInfo>> {
+    let mut queues = Vec::new();
+    let mut page = 1;
+    loop {
+        let url = format!(
+            "{}?page={}&page_size={}&columns=name,type,messages",
+            rabitmq_api_config.vhost_url("queues"),
+            page,
+            QUEUE_PAGE_SIZE
+        );
+        let res = get(rabitmq_api_config, url)
+            .await?
+            .error_for_status()?
+            .json::<QueuePage>()
+            .await?;
+        queues.extend(res.items);
+        //an empty vhost has no pages at all
+        if page >= res.page_count {
+            return Ok(queues);
+        }
+        page += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn config(server: &MockServer, attempts: u32) -> crate::RabbitmqApiConfig {
+        crate::RabbitmqApiConfig {
+            username: "guest".to_string(),
+            password: "guest".to_string(),
+            host: server.address().ip().to_string(),
+            port: server.address().port().to_string(),
+            vhost: "/".to_string(),
+            tls: false,
+            base_url: None,
+            caller_credentials: false,
+            client: reqwest::Client::new(),
+            retry: super::RetryPolicy {
+                attempts,
+                base_delay: Duration::from_millis(1),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let queue = serde_json::json!({"name":"replay","type":"stream","messages":42});
+        //responses that fail every attempt or are never retried, and the requests they take
+        let tests = vec![
+            (2, ResponseTemplate::new(503), 3, Some(42)),
+            (1, ResponseTemplate::new(502), 3, Some(42)),
+            (3, ResponseTemplate::new(503), 3, None),
+            (1, ResponseTemplate::new(500), 1, None),
+            (1, ResponseTemplate::new(401), 3, None),
+            (1, ResponseTemplate::new(404), 3, None),
+        ];
+        for (failing, response, attempts, expected) in tests {
+            let server = MockServer::start().await;
+            let status = response.clone();
+            Mock::given(method("GET"))
+                .and(path("/api/queues/%2F/replay"))
+                .respond_with(response)
+                .up_to_n_times(failing)
+                .expect(failing)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/api/queues/%2F/replay"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&queue))
+                .mount(&server)
+                .await;
+
+            let result = super::get_queue_message_count(&config(&server, attempts), "replay").await;
+            match expected {
+                Some(expected) => assert_eq!(result.unwrap(), Some(expected), "{:?}", status),
+                None => assert!(result.is_err(), "{:?}", status),
+            }
+            server.verify().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_base_url() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/rmq/api/queues/%2F/replay"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"type":"stream","messages":42})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        //host and port are ignored, the proxy prefix is kept
+        let config = crate::RabbitmqApiConfig {
+            host: "unreachable.invalid".to_string(),
+            base_url: Some(format!("{}/rmq/", server.uri())),
+            ..config(&server, 1)
+        };
+        assert_eq!(
+            super::get_queue_message_count(&config, "replay")
+                .await
+                .unwrap(),
+            Some(42)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queue_not_found() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/queues/%2F/replay"))
+            .respond_with(
+                ResponseTemplate::new(404)
+                    .set_body_json(serde_json::json!({"error":"Object Not Found"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let err = super::get_queue_message_count(&config(&server, 3), "replay")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::ReplayError>(),
+            Some(crate::ReplayError::QueueNotFound(queue)) if queue == "replay"
+        ));
+        assert_eq!(err.to_string(), "Queue replay not found");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_message_count() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/queues/%2F/replay"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"type":"stream","messages":-1})),
+            )
+            .mount(&server)
+            .await;
+
+        assert!(
+            super::get_queue_message_count(&config(&server, 3), "replay")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_delay() {
+        let retry = super::RetryPolicy {
+            attempts: 10,
+            base_delay: Duration::from_millis(100),
+        };
+        let tests = vec![
+            (1, 0.0, Duration::from_millis(100)),
+            (2, 0.0, Duration::from_millis(200)),
+            (3, 0.0, Duration::from_millis(400)),
+            (3, 1.0, Duration::from_millis(200)),
+            (3, 0.5, Duration::from_millis(300)),
+            (7, 0.0, super::MAX_RETRY_DELAY),
+            (40, 0.0, super::MAX_RETRY_DELAY),
+            (u32::MAX, 1.0, super::MAX_RETRY_DELAY / 2),
+        ];
+        for (retry_number, jitter, expected) in tests {
+            assert_eq!(
+                retry.delay(retry_number, jitter),
+                expected,
+                "{} {}",
+                retry_number,
+                jitter
+            );
+        }
+        for _ in 0..100 {
+            let jitter = super::jitter();
+            assert!((0.0..1.0).contains(&jitter), "{}", jitter);
+        }
+    }
+
+    #[test]
+    fn test_queue_page() {
+        let page: super::QueuePage = serde_json::from_str(
+            r#"{"filtered_count":2,"item_count":2,"page":1,"page_count":3,"page_size":100,"total_count":202,"items":[{"name":"replay","type":"stream","messages":500},{"name":"orders","type":"classic"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(page.page_count, 3);
+        assert_eq!(
+            page.items,
+            vec![
+                super::QueueInfo {
+                    name: "replay".to_string(),
+                    queue_type: Some("stream".to_string()),
+                    messages: Some(500),
+                },
+                super::QueueInfo {
+                    name: "orders".to_string(),
+                    queue_type: Some("classic".to_string()),
+                    messages: None,
+                },
+            ]
+        );
+
+        let page: super::QueuePage = serde_json::from_str(
+            r#"{"filtered_count":0,"item_count":0,"page":1,"page_count":0,"page_size":100,"total_count":0,"items":[]}"#,
+        )
+        .unwrap();
+        assert_eq!(page.page_count, 0);
+        assert!(page.items.is_empty());
+    }
+}
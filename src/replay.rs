@@ -1,6 +1,13 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    ops::RangeInclusive,
+};
+
 use chrono::{TimeZone, Utc};
 use lapin::message::Delivery;
-use lapin::options::BasicAckOptions;
+use lapin::options::{
+    BasicAckOptions, BasicCancelOptions, BasicPublishOptions, ConfirmSelectOptions,
+};
 use lapin::types::AMQPValue::{self};
 use lapin::{
     options::{BasicConsumeOptions, BasicQosOptions},
@@ -8,33 +15,473 @@ use lapin::{
 };
 
 use anyhow::{anyhow, Result};
-use futures_lite::{stream, StreamExt};
-use serde::Serialize;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use futures_lite::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
 
-use crate::{HeaderReplay, MessageOptions, MessageQuery, RabbitmqApiConfig, TimeFrameReplay};
+use crate::{
+    management::{get_exchange_type, get_queue_details, get_queue_message_count, list_queues},
+    validate_header_name, AMQPHeader, AmqpManager, AmqpPool, ConsumerArgs, CorrelationIdFilter,
+    DeadLetterFilter, FilteredReplay, HeaderMatch, HeaderReplay, MatchType, MessageOptions,
+    MessageQuery, OffsetReplay, Pacing, RabbitmqApiConfig, ReplayError, ReplayMode, ReplayOrder,
+    ReplayTarget, SpillOptions, TimeFrameReplay,
+};
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Message {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub offset: Option<u64>,
-    pub transaction: Option<TransactionHeader>,
+    //one entry per configured transaction header the message carries
+    #[serde(default)]
+    pub transactions: Vec<TransactionHeader>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_id: Option<String>,
+    //exchange and routing key the message was published to: the original publish for listed
+    //messages, the copy's for replayed ones. Unset for fan-out replays, whose summary lists the
+    //targets instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_to: Option<ReplayTarget>,
+    //set for copies republished by a replay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replayed: Option<Provenance>,
     pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    //size of the raw payload, before it was decoded into `data`
+    #[serde(default)]
+    pub size_bytes: usize,
+    #[serde(default)]
+    pub encoding: PayloadEncoding,
     pub data: String,
 }
 
-#[derive(Serialize, Debug)]
+//how the payload is rendered in `data`. The raw bytes are what is published on replay, this is
+//only their representation in the JSON responses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl PayloadEncoding {
+    //payloads that aren't valid UTF-8, like protobuf or compressed messages, are base64 encoded
+    pub fn encode(data: Vec<u8>, force_base64: bool) -> (PayloadEncoding, String) {
+        let data = match String::from_utf8(data) {
+            Ok(data) if !force_base64 => return (PayloadEncoding::Utf8, data),
+            Ok(data) => data.into_bytes(),
+            Err(err) => err.into_bytes(),
+        };
+        (PayloadEncoding::Base64, BASE64_STANDARD.encode(data))
+    }
+}
+
+impl Message {
+    //replaces the values of the listed transaction headers and the data if `body` is listed
+    pub fn redact(&mut self, fields: &[&str]) {
+        for transaction in &mut self.transactions {
+            if fields.contains(&transaction.name.as_str()) {
+                transaction.value = REDACTED.to_string();
+            }
+        }
+        if fields.contains(&"body") {
+            self.encoding = PayloadEncoding::Utf8;
+            self.data = REDACTED.to_string();
+        }
+    }
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+//stamped on every replayed message, a later replay tells the copies from the originals by them
+pub const REPLAYED_FROM_OFFSET_HEADER: &str = "x-replayed-from-offset";
+pub const REPLAYED_ORIGINAL_TIMESTAMP_HEADER: &str = "x-replayed-original-timestamp";
+pub const REPLAYED_AT_HEADER: &str = "x-replayed-at";
+
+//where a replayed copy came from. The timestamps are carried as milliseconds like the
+//timestamp property
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Provenance {
+    pub from_offset: u64,
+    pub original_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub replayed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Provenance {
+    //`None` for messages that weren't republished by a replay
+    pub fn from_properties(properties: &lapin::BasicProperties) -> Option<Self> {
+        let headers = properties.headers().as_ref()?.inner();
+        let timestamp = |name: &str| {
+            headers
+                .get(name)
+                .and_then(amqp_value_to_u64)
+                .and_then(|millis| i64::try_from(millis).ok())
+                .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+        };
+        Some(Self {
+            from_offset: headers
+                .get(REPLAYED_FROM_OFFSET_HEADER)
+                .and_then(amqp_value_to_u64)?,
+            original_timestamp: timestamp(REPLAYED_ORIGINAL_TIMESTAMP_HEADER),
+            replayed_at: timestamp(REPLAYED_AT_HEADER),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamOffsets {
+    pub queue: String,
+    pub messages: u64,
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+    pub first_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConsumerOffset {
+    pub consumer_tag: String,
+    pub current_offset: u64,
+    pub lag: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TransactionHeader {
     pub name: String,
     pub value: String,
 }
 
+//filters applied to every delivery on top of the time frame or header of a request
+#[derive(Debug, Default)]
+pub struct MessageFilter {
+    pub dead_letter: Option<DeadLetterFilter>,
+    pub priority: Option<RangeInclusive<u8>>,
+    pub correlation_id: Option<CorrelationIdFilter>,
+    //exact app_id, messages without an app_id never match
+    pub app_id: Option<String>,
+    //path of a header the message must not carry, a null value counts as missing
+    pub header_absent: Option<Vec<ShortString>>,
+    //leaves out the copies republished by an earlier replay
+    pub skip_replayed: bool,
+}
+
+impl MessageFilter {
+    pub fn matches(&self, delivery: &Delivery) -> bool {
+        if self.skip_replayed && Provenance::from_properties(&delivery.properties).is_some() {
+            return false;
+        }
+        if let Some(path) = &self.header_absent {
+            let value = delivery
+                .properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| lookup_header_path(headers, path));
+            if !matches!(value, None | Some(AMQPValue::Void)) {
+                return false;
+            }
+        }
+        if let Some(filter) = &self.correlation_id {
+            let matches = match delivery.properties.correlation_id() {
+                Some(correlation_id) if filter.prefix => {
+                    correlation_id.as_str().starts_with(filter.value.as_str())
+                }
+                Some(correlation_id) => correlation_id.as_str() == filter.value,
+                None => false,
+            };
+            if !matches {
+                return false;
+            }
+        }
+        if let Some(app_id) = &self.app_id {
+            match delivery.properties.app_id() {
+                Some(message_app_id) if message_app_id.as_str() == app_id => {}
+                _ => return false,
+            }
+        }
+        if let Some(priority) = &self.priority {
+            //messages without a priority are treated as priority 0
+            let message_priority = delivery.properties.priority().unwrap_or(0);
+            if !priority.contains(&message_priority) {
+                return false;
+            }
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            let headers = match delivery.properties.headers() {
+                Some(headers) => headers,
+                None => return false,
+            };
+            if !parse_x_death(headers)
+                .iter()
+                .any(|death| death.matches(dead_letter))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+//validates the name of the header that has to be absent and splits it into its lookup path
+pub fn header_absent_path(name: Option<&str>) -> Result<Option<Vec<ShortString>>, ReplayError> {
+    name.map(|name| validate_header_name(name).map(|_| header_path(name)))
+        .transpose()
+}
+
+//validates the requested priority bounds, AMQP priorities are in the range 0 to 255
+pub fn priority_range(
+    min_priority: Option<u16>,
+    max_priority: Option<u16>,
+) -> Result<Option<RangeInclusive<u8>>, ReplayError> {
+    if min_priority.is_none() && max_priority.is_none() {
+        return Ok(None);
+    }
+    let to_priority = |priority: u16| {
+        u8::try_from(priority).map_err(|_| {
+            ReplayError::InvalidPriorityRange(format!(
+                "priority {} is above the maximum of 255",
+                priority
+            ))
+        })
+    };
+    let min_priority = to_priority(min_priority.unwrap_or(0))?;
+    let max_priority = to_priority(max_priority.unwrap_or(255))?;
+    if min_priority > max_priority {
+        return Err(ReplayError::InvalidPriorityRange(format!(
+            "min_priority {} is greater than max_priority {}",
+            min_priority, max_priority
+        )));
+    }
+    Ok(Some(min_priority..=max_priority))
+}
+
+//validates that the requested time frame doesn't end before it starts
+pub fn time_range(
+    from: Option<chrono::DateTime<Utc>>,
+    to: Option<chrono::DateTime<Utc>>,
+) -> Result<(), ReplayError> {
+    match (from, to) {
+        (Some(from), Some(to)) if from > to => Err(ReplayError::InvalidTimeRange(from, to)),
+        _ => Ok(()),
+    }
+}
+
+//validates the requested payload size bounds
+pub fn size_range(
+    min_bytes: Option<usize>,
+    max_bytes: Option<usize>,
+) -> Result<Option<RangeInclusive<usize>>, ReplayError> {
+    if min_bytes.is_none() && max_bytes.is_none() {
+        return Ok(None);
+    }
+    let min_bytes = min_bytes.unwrap_or(0);
+    let max_bytes = max_bytes.unwrap_or(usize::MAX);
+    if min_bytes > max_bytes {
+        return Err(ReplayError::InvalidSizeRange(min_bytes, max_bytes));
+    }
+    Ok(Some(min_bytes..=max_bytes))
+}
+
+//matches the payload of a message. Payloads that aren't valid UTF-8, or valid JSON for a
+//pointer, don't match instead of failing the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyFilter {
+    contains: Option<String>,
+    json_eq: Option<(String, serde_json::Value)>,
+}
+
+impl BodyFilter {
+    fn matches(&self, data: &[u8]) -> bool {
+        if let Some(contains) = &self.contains {
+            match std::str::from_utf8(data) {
+                Ok(body) if body.contains(contains.as_str()) => {}
+                _ => return false,
+            }
+        }
+        if let Some((pointer, value)) = &self.json_eq {
+            match serde_json::from_slice::<serde_json::Value>(data) {
+                Ok(body) if body.pointer(pointer) == Some(value) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+//validates the body filters of a query, a pointer and the value it is compared with only
+//come together
+pub fn body_filter(
+    contains: Option<&str>,
+    json_pointer: Option<&str>,
+    json_eq: Option<&str>,
+) -> Result<Option<BodyFilter>, ReplayError> {
+    let json_eq = match (json_pointer, json_eq) {
+        (None, None) => None,
+        (Some(pointer), Some(value)) => {
+            if !pointer.is_empty() && !pointer.starts_with('/') {
+                return Err(ReplayError::InvalidBodyFilter(format!(
+                    "body_json_pointer {:?} has to start with /",
+                    pointer
+                )));
+            }
+            let value = serde_json::from_str(value)
+                .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+            Some((pointer.to_string(), value))
+        }
+        (Some(_), None) => {
+            return Err(ReplayError::InvalidBodyFilter(
+                "body_json_pointer requires body_json_eq".to_string(),
+            ))
+        }
+        (None, Some(_)) => {
+            return Err(ReplayError::InvalidBodyFilter(
+                "body_json_eq requires body_json_pointer".to_string(),
+            ))
+        }
+    };
+    if contains.is_none() && json_eq.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(BodyFilter {
+        contains: contains.map(str::to_string),
+        json_eq,
+    }))
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DeathRecord {
+    pub queue: Option<String>,
+    pub reason: Option<String>,
+    pub count: u64,
+}
+
+impl DeathRecord {
+    fn matches(&self, filter: &DeadLetterFilter) -> bool {
+        filter
+            .reason
+            .as_ref()
+            .map_or(true, |reason| self.reason.as_ref() == Some(reason))
+            && filter
+                .original_queue
+                .as_ref()
+                .map_or(true, |queue| self.queue.as_ref() == Some(queue))
+            && filter
+                .min_count
+                .map_or(true, |min_count| self.count >= min_count)
+    }
+}
+
+//parses the x-death header RabbitMQ adds when dead-lettering a message. It is an array with
+//one table per queue and reason, but brokers before 3.8 don't always set the count and some
+//clients republish a single entry as a plain table.
+pub fn parse_x_death(headers: &FieldTable) -> Vec<DeathRecord> {
+    let entries: Vec<&AMQPValue> = match headers.inner().get("x-death") {
+        Some(AMQPValue::FieldArray(entries)) => entries.as_slice().iter().collect(),
+        Some(entry @ AMQPValue::FieldTable(_)) => vec![entry],
+        _ => return Vec::new(),
+    };
+    entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            AMQPValue::FieldTable(entry) => Some(DeathRecord {
+                queue: entry.inner().get("queue").and_then(amqp_value_to_string),
+                reason: entry.inner().get("reason").and_then(amqp_value_to_string),
+                count: entry
+                    .inner()
+                    .get("count")
+                    .and_then(amqp_value_to_u64)
+                    .unwrap_or(1),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn amqp_value_to_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        AMQPValue::ByteArray(value) => Some(String::from_utf8_lossy(value.as_slice()).into_owned()),
+        _ => None,
+    }
+}
+
+//strings, booleans and integers of any width, the integers in their decimal form
+fn amqp_scalar_to_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::LongString(value) => Some(value.to_string()),
+        AMQPValue::ShortString(value) => Some(value.to_string()),
+        AMQPValue::Boolean(value) => Some(value.to_string()),
+        AMQPValue::ShortShortInt(value) => Some(value.to_string()),
+        AMQPValue::ShortShortUInt(value) => Some(value.to_string()),
+        AMQPValue::ShortInt(value) => Some(value.to_string()),
+        AMQPValue::ShortUInt(value) => Some(value.to_string()),
+        AMQPValue::LongInt(value) => Some(value.to_string()),
+        AMQPValue::LongUInt(value) => Some(value.to_string()),
+        AMQPValue::LongLongInt(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn amqp_value_to_u64(value: &AMQPValue) -> Option<u64> {
+    match *value {
+        AMQPValue::ShortShortInt(n) => u64::try_from(n).ok(),
+        AMQPValue::ShortShortUInt(n) => Some(n.into()),
+        AMQPValue::ShortInt(n) => u64::try_from(n).ok(),
+        AMQPValue::ShortUInt(n) => Some(n.into()),
+        AMQPValue::LongInt(n) => u64::try_from(n).ok(),
+        AMQPValue::LongUInt(n) => Some(n.into()),
+        AMQPValue::LongLongInt(n) => u64::try_from(n).ok(),
+        AMQPValue::Timestamp(n) => Some(n),
+        _ => None,
+    }
+}
+
+//producers set transaction ids as strings, integers of any width, booleans or raw bytes. An
+//array stands for its first element and a table for its entries joined as `key=value`
+fn transaction_id_to_string(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::FieldArray(values) => {
+            values.as_slice().first().and_then(transaction_id_to_string)
+        }
+        AMQPValue::FieldTable(table) => {
+            let entries: Vec<String> = table
+                .inner()
+                .iter()
+                .filter_map(|(key, value)| {
+                    transaction_id_to_string(value).map(|value| format!("{}={}", key, value))
+                })
+                .collect();
+            (!entries.is_empty()).then(|| entries.join(","))
+        }
+        value => amqp_value_to_string(value).or_else(|| amqp_scalar_to_string(value)),
+    }
+}
+
 impl TransactionHeader {
-    pub fn from_fieldtable(field_table: FieldTable, header_name: &str) -> Result<Self> {
-        let transaction_id = match field_table.inner().get(header_name) {
-            Some(AMQPValue::LongString(transaction_id)) => transaction_id.to_string(),
-            _ => return Err(anyhow!("Transaction header {} not found", header_name)),
+    //`None` if the message doesn't carry the header or carries it with a null value, an error if
+    //its value can't be read as a transaction id
+    pub fn from_fieldtable(field_table: FieldTable, header_name: &str) -> Result<Option<Self>> {
+        let value = match field_table.inner().get(header_name) {
+            None | Some(AMQPValue::Void) => return Ok(None),
+            Some(value) => value,
         };
-        Ok(Self {
+        match transaction_id_to_string(value) {
+            Some(transaction_id) => Ok(Some(Self {
+                name: header_name.to_string(),
+                value: transaction_id,
+            })),
+            None => Err(anyhow!(
+                "Transaction header {} holds {:?}, which can't be read as a transaction id",
+                header_name,
+                value
+            )),
+        }
+    }
+
+    //the transaction header of a message, `None` if it is missing or can't be read as a string
+    pub fn lookup(headers: &FieldTable, header_name: &str) -> Option<Self> {
+        let transaction_id = transaction_id_to_string(headers.inner().get(header_name)?)?;
+        Some(Self {
             name: header_name.to_string(),
             value: transaction_id,
         })
@@ -42,389 +489,4755 @@ impl TransactionHeader {
 }
 
 pub async fn replay_time_frame(
-    pool: &deadpool_lapin::Pool,
+    pool: &AmqpPool,
     rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
     time_frame: TimeFrameReplay,
 ) -> Result<Vec<Delivery>> {
-    let message_count =
-        match get_queue_message_count(rabbitmq_api_config, &time_frame.queue).await? {
-            Some(message_count) => message_count,
-            None => return Err(anyhow!("Queue not found or empty")),
-        };
+    Ok(
+        scan_time_frame(pool, rabbitmq_api_config, message_options, time_frame, None)
+            .await?
+            .messages,
+    )
+}
 
-    let connection = pool.get().await?;
-    let channel = connection.create_channel().await?;
+//the messages of a time frame replay, `scan_truncated` is set if the scan stopped at
+//`max_scan_messages` before it reached the end of the stream
+pub struct TimeFrameScan {
+    pub messages: Vec<Delivery>,
+    pub scan_truncated: bool,
+}
 
-    //set prefetch count to 1000
-    channel
-        .basic_qos(1000u16, BasicQosOptions { global: false })
-        .await?;
+#[tracing::instrument(skip_all, fields(
+    queue = %time_frame.queue,
+    from = %time_frame.from,
+    to = %time_frame.to,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn scan_time_frame(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    time_frame: TimeFrameReplay,
+    max_messages: Option<u64>,
+) -> Result<TimeFrameScan> {
+    let is_match = time_frame_matcher(&time_frame)?;
+    let consumer_args = consumer_args_table(time_frame.consumer_args.as_ref())?;
 
-    let mut consumer = channel
-        .basic_consume(
-            &time_frame.queue,
-            "replay",
-            BasicConsumeOptions::default(),
-            stream_consume_args(AMQPValue::LongString("first".into())),
-        )
-        .await?;
+    let mut messages = MatchedMessages::new(max_messages, order_window_limit(time_frame.order_by));
+    let scan_truncated = consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &time_frame.queue,
+        "replay",
+        consumer_args,
+        Some(time_frame.from),
+        time_frame.max_scan_messages,
+        is_match,
+        |delivery, _| messages.push(delivery),
+    )
+    .await?;
+    Ok(TimeFrameScan {
+        messages: order_messages(
+            messages.into_messages()?,
+            time_frame.order_by,
+            time_frame.exclude_missing_timestamps.unwrap_or(false),
+        ),
+        scan_truncated,
+    })
+}
 
-    let mut messages = Vec::new();
-    while let Some(Ok(delivery)) = consumer.next().await {
-        delivery.ack(BasicAckOptions::default()).await?;
-        let headers = match delivery.properties.headers().as_ref() {
-            Some(headers) => headers,
-            None => return Err(anyhow!("No headers found")),
-        };
-        let offset = match headers.inner().get("x-stream-offset") {
-            Some(AMQPValue::LongLongInt(offset)) => offset,
-            _ => return Err(anyhow!("x-stream-offset not found")),
-        };
-        let timestamp = *delivery.properties.timestamp();
+//upper bound for the matched messages of a replay ordered by timestamp. the whole window has
+//to be held in memory and sorted before the first message is published
+pub const MAX_TIMESTAMP_ORDER_MESSAGES: usize = 100_000;
 
-        match is_within_timeframe(timestamp, Some(time_frame.from), Some(time_frame.to)) {
-            Some(true) => {
-                if *offset >= i64::try_from(message_count - 1)? {
-                    messages.push(delivery);
-                    break;
-                }
-                messages.push(delivery);
-            }
-            _ => {
-                if *offset >= i64::try_from(message_count - 1)? {
-                    break;
-                }
-                continue;
+fn order_window_limit(order_by: Option<ReplayOrder>) -> Option<usize> {
+    match order_by.unwrap_or_default() {
+        ReplayOrder::Offset => None,
+        ReplayOrder::Timestamp => Some(MAX_TIMESTAMP_ORDER_MESSAGES),
+    }
+}
+
+//the matched messages of a replay. Past `max_messages` they are only counted and no longer
+//kept, so a replay over the cap is refused after a single pass without holding all of them
+struct MatchedMessages {
+    messages: Vec<Delivery>,
+    matched: u64,
+    max_messages: Option<u64>,
+    window_limit: Option<usize>,
+}
+
+impl MatchedMessages {
+    fn new(max_messages: Option<u64>, window_limit: Option<usize>) -> Self {
+        Self {
+            messages: Vec::new(),
+            matched: 0,
+            max_messages,
+            window_limit,
+        }
+    }
+
+    fn push(&mut self, delivery: Delivery) -> Result<()> {
+        self.matched += 1;
+        if self
+            .max_messages
+            .is_some_and(|max_messages| self.matched > max_messages)
+        {
+            self.messages = Vec::new();
+            return Ok(());
+        }
+        if let Some(limit) = self
+            .window_limit
+            .filter(|limit| self.messages.len() >= *limit)
+        {
+            return Err(ReplayError::OrderWindowTooLarge(limit).into());
+        }
+        self.messages.push(delivery);
+        Ok(())
+    }
+
+    fn into_messages(self) -> Result<Vec<Delivery>> {
+        match self.max_messages {
+            Some(max_messages) if self.matched > max_messages => {
+                Err(ReplayError::TooManyMessages(self.matched, max_messages).into())
             }
+            _ => Ok(self.messages),
         }
     }
-    Ok(messages)
 }
 
-pub async fn fetch_messages(
-    pool: &deadpool_lapin::Pool,
+//sorts the messages by their timestamp property if asked to. the messages come in offset order
+//and the sort is stable, so messages with equal timestamps keep their offset order. messages
+//without a timestamp are published last or left out
+pub fn order_messages(
+    messages: Vec<Delivery>,
+    order_by: Option<ReplayOrder>,
+    exclude_missing_timestamps: bool,
+) -> Vec<Delivery> {
+    if order_by.unwrap_or_default() == ReplayOrder::Offset {
+        return messages;
+    }
+    let mut messages: Vec<Delivery> = messages
+        .into_iter()
+        .filter(|delivery| !exclude_missing_timestamps || delivery.properties.timestamp().is_some())
+        .collect();
+    messages.sort_by_key(|delivery| {
+        let timestamp = *delivery.properties.timestamp();
+        (timestamp.is_none(), timestamp)
+    });
+    messages
+}
+
+fn time_frame_matcher(time_frame: &TimeFrameReplay) -> Result<impl Fn(&Delivery, i64) -> bool> {
+    time_range(Some(time_frame.from), Some(time_frame.to))?;
+    let filter = MessageFilter {
+        dead_letter: time_frame.dead_letter.clone(),
+        priority: priority_range(time_frame.min_priority, time_frame.max_priority)?,
+        correlation_id: time_frame.correlation_id.clone(),
+        app_id: time_frame.app_id.clone(),
+        header_absent: header_absent_path(time_frame.header_absent.as_deref())?,
+        skip_replayed: time_frame.skip_replayed.unwrap_or(false),
+    };
+    let exclude_offsets: HashSet<u64> = time_frame
+        .exclude_offsets
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+    let (from, to) = (time_frame.from, time_frame.to);
+
+    Ok(move |delivery: &Delivery, offset: i64| {
+        let excluded = u64::try_from(offset).is_ok_and(|offset| exclude_offsets.contains(&offset));
+        filter.matches(delivery)
+            && !excluded
+            && is_within_timeframe(*delivery.properties.timestamp(), Some(from), Some(to))
+                == Some(true)
+    })
+}
+
+//consumes the stream from the first message, or from about `seek_to` if given, up to the last
+//offset counted by the management API and hands every delivery matched by `is_match` to
+//`visit`, together with its offset.
+//returns true if the scan stopped after `max_scan_messages` deliveries before the last offset
+#[allow(clippy::too_many_arguments)]
+async fn consume_stream<M, V>(
+    pool: &AmqpPool,
     rabbitmq_api_config: &RabbitmqApiConfig,
     message_options: &MessageOptions,
-    message_query: MessageQuery,
-) -> Result<Vec<Message>> {
-    let message_count =
-        match get_queue_message_count(rabbitmq_api_config, message_query.queue.as_str()).await? {
+    queue: &str,
+    consumer_tag: &str,
+    consumer_args: FieldTable,
+    seek_to: Option<chrono::DateTime<Utc>>,
+    max_scan_messages: Option<u64>,
+    is_match: M,
+    mut visit: V,
+) -> Result<bool>
+where
+    M: Fn(&Delivery, i64) -> bool,
+    V: FnMut(Delivery, i64) -> Result<()>,
+{
+    let mut scan = StreamScan::start(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        queue,
+        consumer_tag,
+        consumer_args,
+        seek_to,
+        max_scan_messages,
+        is_match,
+    )
+    .await?;
+    while let Some((delivery, offset)) = scan.next().await? {
+        visit(delivery, offset)?;
+    }
+    Ok(scan.truncated)
+}
+
+//the consumer behind `consume_stream`, pulled one matched delivery at a time so a caller can
+//hand the deliveries on without collecting them first
+struct StreamScan<M> {
+    //`None` once the last offset was reached, or right away for an empty stream
+    consumer: Option<lapin::Consumer>,
+    //held for the consumer, the connection goes back to the pool when the scan is dropped
+    _channel: Option<lapin::Channel>,
+    _connection: Option<deadpool::managed::Object<AmqpManager>>,
+    message_count: u64,
+    max_scan_messages: Option<u64>,
+    is_match: M,
+    //a scan waiting longer for the next delivery stops with what it read so far
+    idle_timeout: std::time::Duration,
+    progress: Progress,
+    first_offset: Option<i64>,
+    scanned: u64,
+    //set if the scan stopped after `max_scan_messages` deliveries before the last offset
+    truncated: bool,
+}
+
+impl<M> StreamScan<M>
+where
+    M: Fn(&Delivery, i64) -> bool,
+{
+    #[allow(clippy::too_many_arguments)]
+    async fn start(
+        pool: &AmqpPool,
+        rabbitmq_api_config: &RabbitmqApiConfig,
+        message_options: &MessageOptions,
+        queue: &str,
+        consumer_tag: &str,
+        consumer_args: FieldTable,
+        seek_to: Option<chrono::DateTime<Utc>>,
+        max_scan_messages: Option<u64>,
+        is_match: M,
+    ) -> Result<Self> {
+        let message_count = match get_queue_message_count(rabbitmq_api_config, queue).await? {
             Some(message_count) => message_count,
-            None => {
-                return Err(anyhow!("Queue not found or empty"));
-            }
+            None => return Err(anyhow!("Queue not found or empty")),
         };
+        let mut scan = Self::new(
+            message_options,
+            queue,
+            consumer_tag,
+            message_count,
+            max_scan_messages,
+            is_match,
+        );
 
-    let connection = pool.get().await?;
-    let channel = connection.create_channel().await?;
+        //an empty stream has no last offset to stop at, the consumer would wait forever
+        if message_count == 0 {
+            return Ok(scan);
+        }
 
-    //set prefetch count to 1000
-    channel
-        .basic_qos(1000u16, BasicQosOptions { global: false })
-        .await?;
+        let connection = pool.get().await?;
+        let channel = connection.create_channel().await?;
 
-    let mut consumer = channel
-        .basic_consume(
-            &message_query.queue,
-            "fetch_messages",
-            BasicConsumeOptions::default(),
-            stream_consume_args(AMQPValue::LongString("first".into())),
+        //a scan that doesn't start at the first message counts the offsets from the first one,
+        //else it can't tell when it reached the last message
+        if seek_to.is_some() {
+            channel
+                .basic_qos(1u16, BasicQosOptions { global: false })
+                .await?;
+            let (first_offset, _) = probe_offset(
+                &channel,
+                queue,
+                AMQPValue::LongString("first".into()),
+                0,
+                scan.idle_timeout,
+            )
+            .await?;
+            scan.first_offset = Some(i64::try_from(first_offset)?);
+        }
+
+        scan.consume(
+            connection,
+            channel,
+            queue,
+            consumer_tag,
+            stream_consume_args(stream_start(seek_to), consumer_args),
         )
         .await?;
+        Ok(scan)
+    }
 
-    let mut messages = Vec::new();
+    //attaches at `from_offset` and stops at `to_offset`. The range is counted like the messages
+    //of a whole stream starting at `from_offset`, the deliveries of the chunk before it included
+    async fn start_at_offset(
+        pool: &AmqpPool,
+        message_options: &MessageOptions,
+        queue: &str,
+        consumer_tag: &str,
+        from_offset: u64,
+        to_offset: u64,
+        is_match: M,
+    ) -> Result<Self> {
+        let mut scan = Self::new(
+            message_options,
+            queue,
+            consumer_tag,
+            to_offset - from_offset + 1,
+            None,
+            is_match,
+        );
+        scan.first_offset = Some(i64::try_from(from_offset)?);
 
-    while let Some(Ok(delivery)) = consumer.next().await {
-        delivery.ack(BasicAckOptions::default()).await?;
+        let connection = pool.get().await?;
+        let channel = connection.create_channel().await?;
+        scan.consume(
+            connection,
+            channel,
+            queue,
+            consumer_tag,
+            stream_consume_args(
+                AMQPValue::LongLongInt(i64::try_from(from_offset)?),
+                FieldTable::default(),
+            ),
+        )
+        .await?;
+        Ok(scan)
+    }
 
-        let headers = match delivery.properties.headers().as_ref() {
-            Some(headers) => headers,
-            None => return Err(anyhow!("No headers found")),
-        };
+    fn new(
+        message_options: &MessageOptions,
+        queue: &str,
+        consumer_tag: &str,
+        message_count: u64,
+        max_scan_messages: Option<u64>,
+        is_match: M,
+    ) -> Self {
+        Self {
+            consumer: None,
+            _channel: None,
+            _connection: None,
+            message_count,
+            max_scan_messages,
+            is_match,
+            idle_timeout: message_options.consumer_idle_timeout,
+            progress: Progress::new(consumer_tag, queue, message_options.progress_log),
+            first_offset: None,
+            scanned: 0,
+            truncated: false,
+        }
+    }
 
-        let transaction = match message_options.transaction_header.clone() {
-            Some(transaction_header) => match headers.inner().get(transaction_header.as_str()) {
-                Some(AMQPValue::LongString(transaction_id)) => Some(TransactionHeader {
-                    name: transaction_header,
-                    value: transaction_id.to_string(),
-                }),
-                _ => None,
-            },
-            None => None,
-        };
+    async fn consume(
+        &mut self,
+        connection: deadpool::managed::Object<AmqpManager>,
+        channel: lapin::Channel,
+        queue: &str,
+        consumer_tag: &str,
+        consumer_args: FieldTable,
+    ) -> Result<()> {
+        //set prefetch count to 1000
+        channel
+            .basic_qos(1000u16, BasicQosOptions { global: false })
+            .await?;
 
-        let offset = match headers.inner().get("x-stream-offset") {
-            Some(AMQPValue::LongLongInt(offset)) => offset,
-            _ => return Err(anyhow!("x-stream-offset not found")),
-        };
+        //stream queues refuse consumers with no_ack ("automatic acknowledgement not supported by
+        //stream queues"), so even read-only fetches ack every delivery to keep the credit flowing
+        let consumer = channel
+            .basic_consume(
+                queue,
+                consumer_tag,
+                BasicConsumeOptions::default(),
+                consumer_args,
+            )
+            .await?;
 
-        let timestamp = *delivery.properties.timestamp();
+        self.consumer = Some(consumer);
+        self._channel = Some(channel);
+        self._connection = Some(connection);
+        Ok(())
+    }
 
-        match is_within_timeframe(timestamp, message_query.from, message_query.to) {
-            Some(true) => {
-                if *offset >= i64::try_from(message_count - 1)? {
-                    messages.push(Message {
-                        offset: Some(*offset as u64),
-                        transaction,
-                        timestamp: Some(
-                            //unwrap is safe here, because we checked if time stamp is set
-                            chrono::Utc
-                                .timestamp_millis_opt(timestamp.unwrap() as i64)
-                                .unwrap(),
-                        ),
-                        data: String::from_utf8(delivery.data)?,
-                    });
-                    break;
-                }
-                messages.push(Message {
-                    offset: Some(*offset as u64),
-                    transaction,
-                    timestamp: Some(
-                        //unwrap is safe here, because we checked if time stamp is set
-                        chrono::Utc
-                            .timestamp_millis_opt(timestamp.unwrap() as i64)
-                            .unwrap(),
-                    ),
-                    data: String::from_utf8(delivery.data)?,
-                });
-            }
-            Some(false) => {
-                if *offset >= i64::try_from(message_count - 1)? {
-                    break;
-                }
-                continue;
+    //the next matched delivery and its offset, `None` once the scan is done
+    async fn next(&mut self) -> Result<Option<(Delivery, i64)>> {
+        while let Some(consumer) = &mut self.consumer {
+            if self
+                .max_scan_messages
+                .is_some_and(|max_scan_messages| self.scanned >= max_scan_messages)
+            {
+                self.truncated = true;
+                self.consumer = None;
+                break;
             }
-            None => {
-                if *offset >= i64::try_from(message_count - 1)? {
-                    messages.push(Message {
-                        offset: Some(*offset as u64),
-                        transaction,
-                        timestamp: None,
-                        data: String::from_utf8(delivery.data)?,
-                    });
+            let delivery = match next_delivery(consumer, self.idle_timeout).await {
+                Some(delivery) => delivery,
+                None => {
+                    self.consumer = None;
                     break;
                 }
-                messages.push(Message {
-                    offset: Some(*offset as u64),
-                    transaction,
-                    timestamp: None,
-                    data: String::from_utf8(delivery.data)?,
-                });
+            };
+            self.scanned += 1;
+            delivery.ack(BasicAckOptions::default()).await?;
+            let offset = stream_offset(&delivery)?;
+            //once retention removed old segments the stream no longer starts at offset 0
+            let first_offset = *self.first_offset.get_or_insert(offset);
+            if is_last_offset(offset - first_offset, self.message_count) {
+                self.consumer = None;
+            }
+
+            let matched = (self.is_match)(&delivery, offset);
+            self.progress.scanned(offset, matched);
+            if matched {
+                return Ok(Some((delivery, offset)));
             }
         }
+        Ok(None)
     }
-    Ok(messages)
 }
 
-pub async fn replay_header(
-    pool: &deadpool_lapin::Pool,
+#[tracing::instrument(skip_all, fields(
+    queue = %message_query.queue,
+    from = ?message_query.from,
+    to = ?message_query.to,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn fetch_messages(
+    pool: &AmqpPool,
     rabbitmq_api_config: &RabbitmqApiConfig,
-    header_replay: HeaderReplay,
-) -> Result<Vec<Delivery>> {
-    let message_count =
-        match get_queue_message_count(rabbitmq_api_config, &header_replay.queue).await? {
-            Some(message_count) => message_count,
-            None => return Err(anyhow!("Queue not found or empty")),
-        };
-
-    let connection = pool.get().await?;
+    message_options: &MessageOptions,
+    message_query: MessageQuery,
+) -> Result<Vec<Message>> {
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
 
-    let channel = connection.create_channel().await?;
+    let mut messages = Vec::new();
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "fetch_messages",
+        consumer_args,
+        message_query.from,
+        None,
+        is_match,
+        |delivery, offset| {
+            messages.push(to_message(
+                delivery,
+                offset,
+                message_options,
+                message_query.base64.unwrap_or(false),
+            )?);
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(messages)
+}
 
-    //set prefetch count to 1000
-    channel
-        .basic_qos(1000u16, BasicQosOptions { global: false })
-        .await?;
+//same as `fetch_messages`, but yields the messages one by one as they are consumed instead of
+//collecting them. The stream ends after the first error
+pub async fn fetch_messages_stream(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    message_query: MessageQuery,
+) -> Result<impl Stream<Item = Result<Message>> + Send + 'static> {
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
 
-    let mut consumer = channel
-        .basic_consume(
-            &header_replay.queue,
-            "replay",
-            BasicConsumeOptions::default(),
-            stream_consume_args(AMQPValue::LongString("first".into())),
-        )
-        .await?;
+    let scan = StreamScan::start(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "fetch_messages",
+        consumer_args,
+        message_query.from,
+        None,
+        is_match,
+    )
+    .await?;
+    let force_base64 = message_query.base64.unwrap_or(false);
+    Ok(stream::unfold(
+        Some((scan, message_options.clone())),
+        move |state| async move {
+            let (mut scan, message_options) = state?;
+            let message = match scan.next().await {
+                Ok(Some((delivery, offset))) => {
+                    to_message(delivery, offset, &message_options, force_base64)
+                }
+                Ok(None) => return None,
+                Err(err) => Err(err),
+            };
+            let state = message.is_ok().then_some((scan, message_options));
+            Some((message, state))
+        },
+    ))
+}
 
-    let mut messages = Vec::new();
+//the messages of a fetch serialized as a JSON array, either in memory or, for large results,
+//in a temporary file that is removed when this is dropped
+pub enum FetchedMessages {
+    InMemory(Vec<u8>),
+    Spilled {
+        file: std::fs::File,
+        path: tempfile::TempPath,
+    },
+}
 
-    while let Some(Ok(delivery)) = consumer.next().await {
-        delivery.ack(BasicAckOptions::default()).await?;
-        let headers = match delivery.properties.headers().as_ref() {
-            Some(headers) => headers,
-            None => return Err(anyhow!("No headers found")),
-        };
+//builds the JSON array of a fetch message by message. Once it grows past the threshold the
+//array is moved to a temporary file and continued there, so results larger than the memory of
+//the service can still be returned. Dropping it before `finish` removes the file.
+struct MessageSink {
+    threshold_bytes: u64,
+    dir: std::path::PathBuf,
+    buffer: Vec<u8>,
+    spill: Option<(std::io::BufWriter<std::fs::File>, tempfile::TempPath)>,
+    empty: bool,
+}
 
-        let target_header = headers.inner().get(header_replay.header.name.as_str());
-        let offset = match headers.inner().get("x-stream-offset") {
-            Some(AMQPValue::LongLongInt(offset)) => offset,
-            _ => return Err(anyhow!("Queue is not a stream")),
-        };
+impl MessageSink {
+    fn new(spill_options: &SpillOptions) -> Self {
+        Self {
+            threshold_bytes: spill_options.threshold_bytes,
+            dir: spill_options.dir.clone(),
+            buffer: vec![b'['],
+            spill: None,
+            empty: true,
+        }
+    }
 
-        if *offset >= i64::try_from(message_count - 1)? {
-            if let Some(AMQPValue::LongString(header)) = target_header {
-                if *header.to_string() == header_replay.header.value {
-                    messages.push(delivery);
+    //the consume loop visits deliveries synchronously, writes go through a buffered std file
+    fn add(&mut self, message: &Message) -> Result<()> {
+        let separator: &[u8] = if self.empty { b"" } else { b"," };
+        self.empty = false;
+        match &mut self.spill {
+            Some((writer, _)) => {
+                std::io::Write::write_all(writer, separator)?;
+                serde_json::to_writer(writer, message)?;
+            }
+            None => {
+                self.buffer.extend_from_slice(separator);
+                serde_json::to_writer(&mut self.buffer, message)?;
+                if self.buffer.len() as u64 > self.threshold_bytes {
+                    let (file, path) = tempfile::NamedTempFile::new_in(&self.dir)?.into_parts();
+                    let mut writer = std::io::BufWriter::new(file);
+                    std::io::Write::write_all(&mut writer, &self.buffer)?;
+                    self.buffer = Vec::new();
+                    self.spill = Some((writer, path));
                 }
             }
-            break;
         }
+        Ok(())
+    }
 
-        if let Some(AMQPValue::LongString(header)) = target_header {
-            if *header.to_string() == header_replay.header.value {
-                messages.push(delivery);
+    fn finish(mut self) -> Result<FetchedMessages> {
+        match self.spill.take() {
+            Some((mut writer, path)) => {
+                std::io::Write::write_all(&mut writer, b"]")?;
+                let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+                std::io::Seek::rewind(&mut file)?;
+                Ok(FetchedMessages::Spilled { file, path })
+            }
+            None => {
+                self.buffer.push(b']');
+                Ok(FetchedMessages::InMemory(std::mem::take(&mut self.buffer)))
             }
         }
     }
-    Ok(messages)
 }
 
-async fn get_queue_message_count(
-    rabitmq_api_config: &RabbitmqApiConfig,
-    name: &str,
-) -> Result<Option<u64>> {
-    //AMQP does not provide a way to get meta data about a queue thus the management HTTP API is used.
-    let client = reqwest::Client::new();
+//same as `fetch_messages`, but returns the serialized response and spills it to a temporary
+//file once it is larger than the configured threshold
+#[tracing::instrument(skip_all, fields(
+    queue = %message_query.queue,
+    from = ?message_query.from,
+    to = ?message_query.to,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn fetch_messages_json(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    spill_options: &SpillOptions,
+    message_query: MessageQuery,
+) -> Result<FetchedMessages> {
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
 
-    let url = format!(
-        "http://{}:{}/api/queues/%2f/{}",
-        rabitmq_api_config.host, rabitmq_api_config.port, name
-    );
+    let mut sink = MessageSink::new(spill_options);
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "fetch_messages",
+        consumer_args,
+        message_query.from,
+        None,
+        is_match,
+        |delivery, offset| {
+            sink.add(&to_message(
+                delivery,
+                offset,
+                message_options,
+                message_query.base64.unwrap_or(false),
+            )?)
+        },
+    )
+    .await?;
+    sink.finish()
+}
 
-    let res = client
-        .get(url)
-        .basic_auth(
-            rabitmq_api_config.username.clone(),
-            Some(rabitmq_api_config.password.clone()),
-        )
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
+//a range of offsets, both ends included
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OffsetRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+//offsets up to the last scanned one that are not part of the response. `filtered` offsets were
+//delivered but rejected by the filters, `missing` offsets were never delivered, usually because
+//retention already removed them from the stream
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OffsetGaps {
+    pub filtered: Vec<OffsetRange>,
+    pub missing: Vec<OffsetRange>,
+}
 
-    if let Some(res) = res.get("type") {
-        if res != "stream" {
-            return Err(anyhow!("Queue is not a stream"));
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessagesWithGaps {
+    pub messages: Vec<Message>,
+    pub gaps: OffsetGaps,
+}
+
+//classifies the offsets of a scan, adjacent offsets of the same kind are merged into one range
+#[derive(Default)]
+struct GapTracker {
+    next_offset: u64,
+    gaps: OffsetGaps,
+}
+
+impl GapTracker {
+    //offsets have to be added in increasing order, as they are delivered by the stream
+    fn add(&mut self, offset: u64, matched: bool) {
+        if offset > self.next_offset {
+            push_offsets(&mut self.gaps.missing, self.next_offset, offset - 1);
         }
+        if !matched {
+            push_offsets(&mut self.gaps.filtered, offset, offset);
+        }
+        self.next_offset = self.next_offset.max(offset.saturating_add(1));
     }
+}
 
-    let message_count = res.get("messages");
-
-    match message_count {
-        Some(message_count) => Ok(Some(message_count.as_u64().unwrap())),
-        None => Ok(None),
+fn push_offsets(ranges: &mut Vec<OffsetRange>, start: u64, end: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.end.saturating_add(1) == start => last.end = end,
+        _ => ranges.push(OffsetRange { start, end }),
     }
 }
 
-//publishes the given messages, messages can be published with or without
-//transaction- and timestamp headers depending on the environment variables set.
-pub async fn publish_message(
-    pool: &deadpool_lapin::Pool,
+//same as `fetch_messages`, but also reports which offsets are left out of the response and why
+pub async fn fetch_messages_with_gaps(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
     message_options: &MessageOptions,
-    messages: Vec<Delivery>,
-) -> Result<Vec<Message>> {
-    let connection = pool.get().await?;
-    let channel = connection.create_channel().await?;
-    let mut s = stream::iter(messages);
-    let mut replayed_messages = Vec::new();
+    message_query: MessageQuery,
+) -> Result<MessagesWithGaps> {
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
 
-    while let Some(message) = s.next().await {
-        let mut transaction: Option<TransactionHeader> = None;
-        let mut timestamp: Option<chrono::DateTime<chrono::Utc>> = None;
-        let basic_props = match (
-            message_options.enable_timestamp,
-            message_options.transaction_header.clone(),
-        ) {
-            (true, None) => {
-                timestamp = Some(chrono::Utc::now());
-                let timestamp_u64 = timestamp.unwrap().timestamp_millis() as u64;
-                lapin::BasicProperties::default().with_timestamp(timestamp_u64)
-            }
-            (true, Some(transaction_header)) => {
-                timestamp = Some(chrono::Utc::now());
-                let timestamp_u64 = timestamp.unwrap().timestamp_millis() as u64;
-                let uuid = uuid::Uuid::new_v4().to_string();
-                let mut headers = FieldTable::default();
-                headers.insert(
-                    ShortString::from(transaction_header.as_str()),
-                    AMQPValue::LongString(uuid.as_str().into()),
-                );
-                transaction = TransactionHeader::from_fieldtable(
-                    headers.clone(),
-                    transaction_header.as_str(),
-                )
-                .ok();
-                lapin::BasicProperties::default()
-                    .with_headers(headers)
-                    .with_timestamp(timestamp_u64)
-            }
-            (false, None) => lapin::BasicProperties::default(),
-            (false, Some(transaction_header)) => {
-                let uuid = uuid::Uuid::new_v4().to_string();
-                let mut headers = FieldTable::default();
-                headers.insert(
-                    ShortString::from(transaction_header.as_str()),
-                    AMQPValue::LongString(uuid.as_str().into()),
-                );
-                transaction = TransactionHeader::from_fieldtable(
-                    headers.clone(),
-                    transaction_header.as_str(),
-                )
-                .ok();
-                lapin::BasicProperties::default().with_headers(headers)
+    let mut messages = Vec::new();
+    let mut tracker = GapTracker::default();
+    //every delivery is visited, so offsets rejected by the filters can be told apart from
+    //offsets the stream doesn't have
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "fetch_messages",
+        consumer_args,
+        None,
+        None,
+        |_: &Delivery, _: i64| true,
+        |delivery, offset| {
+            let matched = is_match(&delivery, offset);
+            if let Ok(offset) = u64::try_from(offset) {
+                tracker.add(offset, matched);
+            }
+            if matched {
+                messages.push(to_message(
+                    delivery,
+                    offset,
+                    message_options,
+                    message_query.base64.unwrap_or(false),
+                )?);
+            }
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(MessagesWithGaps {
+        messages,
+        gaps: tracker.gaps,
+    })
+}
+
+//unlike a time frame replay, messages without a timestamp are listed
+fn message_query_matcher(
+    message_query: &MessageQuery,
+    message_options: &MessageOptions,
+) -> Result<impl Fn(&Delivery, i64) -> bool> {
+    let filter = MessageFilter {
+        dead_letter: message_query.dead_letter(),
+        priority: priority_range(message_query.min_priority, message_query.max_priority)?,
+        correlation_id: message_query.correlation_id(),
+        app_id: message_query.app_id.clone(),
+        header_absent: header_absent_path(message_query.header_absent.as_deref())?,
+        skip_replayed: false,
+    };
+    //`transaction_id` matches if any of the configured transaction headers carries it
+    let transaction = match (
+        &message_query.transaction_id,
+        message_options.transaction_headers.as_slice(),
+    ) {
+        (Some(_), []) => return Err(ReplayError::TransactionHeaderNotConfigured.into()),
+        (Some(transaction_id), headers) => Some((headers.to_vec(), transaction_id.clone())),
+        (None, _) => None,
+    };
+    let size = size_range(message_query.min_bytes, message_query.max_bytes)?;
+    let body = body_filter(
+        message_query.body_contains.as_deref(),
+        message_query.body_json_pointer.as_deref(),
+        message_query.body_json_eq.as_deref(),
+    )?;
+    time_range(message_query.from, message_query.to)?;
+    let (from, to) = (message_query.from, message_query.to);
+
+    Ok(move |delivery: &Delivery, _: i64| {
+        if size
+            .as_ref()
+            .is_some_and(|size| !size.contains(&delivery.data.len()))
+        {
+            return false;
+        }
+        let is_transaction = match &transaction {
+            Some((headers, transaction_id)) => {
+                delivery.properties.headers().as_ref().is_some_and(|table| {
+                    headers.iter().any(|header| {
+                        table
+                            .inner()
+                            .get(header.as_str())
+                            .is_some_and(|value| header_value_matches(value, transaction_id))
+                    })
+                })
             }
+            None => true,
         };
+        //the payload is decoded last, after the cheaper filters had their say
+        is_transaction
+            && filter.matches(delivery)
+            && is_within_timeframe(*delivery.properties.timestamp(), from, to) != Some(false)
+            && body
+                .as_ref()
+                .map_or(true, |body| body.matches(&delivery.data))
+    })
+}
+
+//converts a delivery consumed from a stream into the message returned by the API
+fn to_message(
+    delivery: Delivery,
+    offset: i64,
+    message_options: &MessageOptions,
+    force_base64: bool,
+) -> Result<Message> {
+    let transactions = match delivery.properties.headers() {
+        Some(headers) => message_options
+            .transaction_headers
+            .iter()
+            .filter_map(|transaction_header| TransactionHeader::lookup(headers, transaction_header))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let timestamp = delivery
+        .properties
+        .timestamp()
+        .map(|timestamp| Utc.timestamp_millis_opt(timestamp as i64).unwrap());
+
+    let app_id = delivery
+        .properties
+        .app_id()
+        .as_ref()
+        .map(|app_id| app_id.to_string());
+
+    //a stream delivery carries the exchange and routing key of the original publish
+    let published_to = Some(ReplayTarget {
+        exchange: delivery.exchange.to_string(),
+        routing_key: delivery.routing_key.to_string(),
+    });
+
+    let size_bytes = delivery.data.len();
+    let (encoding, data) = PayloadEncoding::encode(delivery.data, force_base64);
+
+    let replayed = Provenance::from_properties(&delivery.properties);
+
+    Ok(Message {
+        offset: Some(offset as u64),
+        transactions,
+        app_id,
+        published_to,
+        replayed,
+        timestamp,
+        size_bytes,
+        encoding,
+        data,
+    })
+}
+
+//returns the message at the given offset without acknowledging it
+pub async fn peek_message(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    queue: &str,
+    offset: u64,
+    force_base64: bool,
+) -> Result<Message> {
+    let offsets = stream_offsets(pool, rabbitmq_api_config, message_options, queue).await?;
+    match (offsets.first_offset, offsets.last_offset) {
+        (Some(first_offset), Some(last_offset))
+            if (first_offset..=last_offset).contains(&offset) => {}
+        _ => return Err(ReplayError::OffsetNotFound(queue.to_string(), offset).into()),
+    }
+
+    let connection = pool.get().await?;
+    let channel = connection.create_channel().await?;
+
+    channel
+        .basic_qos(1000u16, BasicQosOptions { global: false })
+        .await?;
+
+    let mut consumer = channel
+        .basic_consume(
+            queue,
+            "peek_message",
+            BasicConsumeOptions::default(),
+            stream_consume_args(
+                AMQPValue::LongLongInt(i64::try_from(offset)?),
+                FieldTable::default(),
+            ),
+        )
+        .await?;
 
+    while let Some(delivery) =
+        next_delivery(&mut consumer, message_options.consumer_idle_timeout).await
+    {
+        let delivery_offset = stream_offset(&delivery)?;
+        //the broker delivers from the start of the chunk containing the offset
+        if delivery_offset < i64::try_from(offset)? {
+            delivery.ack(BasicAckOptions::default()).await?;
+            continue;
+        }
         channel
-            .basic_publish(
-                message.exchange.as_str(),
-                message.routing_key.as_str(),
-                lapin::options::BasicPublishOptions::default(),
-                message.data.as_slice(),
-                basic_props,
-            )
+            .basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default())
             .await?;
+        return to_message(delivery, delivery_offset, message_options, force_base64);
+    }
+    Err(anyhow!(
+        "Consumer closed before offset {} of queue {} was reached",
+        offset,
+        queue
+    ))
+}
 
-        replayed_messages.push(Message {
-            offset: None,
-            transaction,
-            timestamp,
-            data: String::from_utf8(message.data)?,
+//consumes from `from_offset` on instead of scanning the stream from its first message. A range
+//starting after the last offset of the stream is empty.
+#[tracing::instrument(skip_all, fields(
+    queue = %offset_replay.queue,
+    from_offset = offset_replay.from_offset,
+    to_offset = ?offset_replay.to_offset,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn replay_offset(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    offset_replay: OffsetReplay,
+    max_messages: Option<u64>,
+) -> Result<Vec<Delivery>> {
+    let queue = offset_replay.queue.as_str();
+    let offsets = stream_offsets(pool, rabbitmq_api_config, message_options, queue).await?;
+    //the size of the range is known from the offsets, a replay over the cap isn't read at all
+    if let Some(max_messages) = max_messages {
+        let matched = offset_range_len(&offset_replay, offsets.first_offset, offsets.last_offset);
+        if matched > max_messages {
+            return Err(ReplayError::TooManyMessages(matched, max_messages).into());
+        }
+    }
+    let last_offset = match offsets.last_offset {
+        Some(last_offset) => last_offset,
+        None => return Ok(Vec::new()),
+    };
+    let from_offset = offset_replay.from_offset;
+    let to_offset = offset_replay
+        .to_offset
+        .map_or(last_offset, |to_offset| to_offset.min(last_offset));
+    if from_offset > to_offset {
+        return Ok(Vec::new());
+    }
+
+    let mut scan = StreamScan::start_at_offset(
+        pool,
+        message_options,
+        queue,
+        "replay_offset",
+        from_offset,
+        to_offset,
+        //the stream delivers the whole chunk the offset is in, starting before it
+        move |_: &Delivery, offset: i64| {
+            u64::try_from(offset).is_ok_and(|offset| (from_offset..=to_offset).contains(&offset))
+        },
+    )
+    .await?;
+    let mut messages = Vec::new();
+    while let Some((delivery, _)) = scan.next().await? {
+        messages.push(delivery);
+    }
+    Ok(messages)
+}
+
+#[tracing::instrument(skip_all, fields(
+    queue = %header_replay.queue,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn replay_header(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    header_replay: HeaderReplay,
+    max_messages: Option<u64>,
+) -> Result<Vec<Delivery>> {
+    let is_match = header_matcher(&header_replay)?;
+    let consumer_args = consumer_args_table(header_replay.consumer_args.as_ref())?;
+
+    let mut messages =
+        MatchedMessages::new(max_messages, order_window_limit(header_replay.order_by));
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &header_replay.queue,
+        "replay",
+        consumer_args,
+        None,
+        None,
+        is_match,
+        |delivery, _| messages.push(delivery),
+    )
+    .await?;
+    Ok(order_messages(
+        messages.into_messages()?,
+        header_replay.order_by,
+        header_replay.exclude_missing_timestamps.unwrap_or(false),
+    ))
+}
+
+#[tracing::instrument(skip_all, fields(
+    queue = %filtered.queue,
+    from = ?filtered.from,
+    to = ?filtered.to,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn replay_filtered(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    filtered: FilteredReplay,
+    max_messages: Option<u64>,
+) -> Result<Vec<Delivery>> {
+    let is_match = filtered_matcher(&filtered)?;
+
+    let mut messages = MatchedMessages::new(max_messages, None);
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &filtered.queue,
+        "replay",
+        FieldTable::default(),
+        filtered.from,
+        None,
+        is_match,
+        |delivery, _| messages.push(delivery),
+    )
+    .await?;
+    messages.into_messages()
+}
+
+//a message has to be within the time frame and carry the header, each if given
+fn filtered_matcher(filtered: &FilteredReplay) -> Result<impl Fn(&Delivery, i64) -> bool> {
+    time_range(filtered.from, filtered.to)?;
+    let header = filtered
+        .header
+        .as_ref()
+        .map(|header| Ok::<_, ReplayError>((header_path(&header.name), ValueMatcher::new(header)?)))
+        .transpose()?;
+    let (from, to) = (filtered.from, filtered.to);
+
+    Ok(move |delivery: &Delivery, _: i64| {
+        let has_header = header.as_ref().map_or(true, |(path, matcher)| {
+            delivery
+                .properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| lookup_header_path(headers, path))
+                .and_then(header_value_string)
+                .is_some_and(|value| matcher.matches(&value))
         });
+        has_header && is_within_timeframe(*delivery.properties.timestamp(), from, to) != Some(false)
+    })
+}
+
+fn header_matcher(header_replay: &HeaderReplay) -> Result<impl Fn(&Delivery, i64) -> bool> {
+    let filter = MessageFilter {
+        dead_letter: header_replay.dead_letter.clone(),
+        priority: priority_range(header_replay.min_priority, header_replay.max_priority)?,
+        correlation_id: header_replay.correlation_id.clone(),
+        app_id: header_replay.app_id.clone(),
+        header_absent: None,
+        skip_replayed: header_replay.skip_replayed.unwrap_or(false),
+    };
+    //patterns are compiled once here, an invalid one is rejected before the stream is consumed
+    let conditions = header_replay
+        .conditions()
+        .map(|header| Ok((header_path(&header.name), ValueMatcher::new(header)?)))
+        .collect::<Result<Vec<_>, ReplayError>>()?;
+    let header_match = header_replay.header_match.unwrap_or_default();
+
+    Ok(move |delivery: &Delivery, _: i64| {
+        let headers = delivery.properties.headers().as_ref();
+        let matches = |(path, matcher): &(Vec<ShortString>, ValueMatcher)| {
+            headers
+                .and_then(|headers| lookup_header_path(headers, path))
+                .and_then(header_value_string)
+                .is_some_and(|value| matcher.matches(&value))
+        };
+        let is_match = match header_match {
+            HeaderMatch::All => conditions.iter().all(matches),
+            HeaderMatch::Any => conditions.iter().any(matches),
+        };
+        is_match && filter.matches(delivery)
+    })
+}
+
+fn header_value_matches(value: &AMQPValue, expected: &str) -> bool {
+    header_value_string(value).is_some_and(|value| value == expected)
+}
+
+//the header as the request carries it, `None` for types a condition can't match. Producers
+//publish ids and flags as integers of any width or booleans, the request carries them as
+//strings, so integers compare in their decimal form and booleans as `true` or `false`
+fn header_value_string(value: &AMQPValue) -> Option<String> {
+    amqp_scalar_to_string(value)
+}
+
+//the compiled value of a header condition
+enum ValueMatcher {
+    Exact(String),
+    Prefix(String),
+    Regex(regex::Regex),
+}
+
+impl ValueMatcher {
+    fn new(header: &AMQPHeader) -> Result<Self, ReplayError> {
+        Ok(match header.match_type {
+            MatchType::Exact => ValueMatcher::Exact(header.value.clone()),
+            MatchType::Prefix => ValueMatcher::Prefix(header.value.clone()),
+            MatchType::Regex => {
+                ValueMatcher::Regex(regex::Regex::new(&header.value).map_err(|err| {
+                    ReplayError::InvalidHeaderPattern(header.value.clone(), err.to_string())
+                })?)
+            }
+        })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatcher::Exact(expected) => value == expected,
+            ValueMatcher::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            ValueMatcher::Regex(regex) => regex.is_match(value),
+        }
     }
-    Ok(replayed_messages)
 }
 
-fn stream_consume_args(stream_offset: AMQPValue) -> FieldTable {
-    let mut args = FieldTable::default();
-    args.insert(ShortString::from("x-stream-offset"), stream_offset);
-    args
+//upper bound for the transaction values tracked while looking for duplicates
+pub const MAX_DUPLICATE_GROUPS: usize = 100_000;
+//upper bound for the offsets listed per duplicate group, the count stays exact
+pub const MAX_DUPLICATE_OFFSETS: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub value: String,
+    pub count: u64,
+    pub offsets: Vec<u64>,
 }
 
-fn is_within_timeframe(
-    date: Option<u64>,
-    from: Option<chrono::DateTime<chrono::Utc>>,
-    to: Option<chrono::DateTime<chrono::Utc>>,
-) -> Option<bool> {
-    match date {
-        Some(date) => {
-            let date = Utc.timestamp_millis_opt(date as i64).unwrap();
-            match (from, to) {
-                (Some(from), Some(to)) => Some(date >= from && date <= to),
-                (Some(from), None) => Some(date >= from),
-                (None, Some(to)) => Some(date <= to),
-                (None, None) => Some(true),
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DuplicateReport {
+    pub header: String,
+    pub groups: Vec<DuplicateGroup>,
+    pub without_header: u64,
+    //set once more than `MAX_DUPLICATE_GROUPS` distinct values were seen, values seen after
+    //that are not tracked
+    pub truncated: bool,
+}
+
+#[derive(Debug, Default)]
+struct DuplicateTracker {
+    groups: std::collections::HashMap<String, DuplicateGroup>,
+    without_header: u64,
+    truncated: bool,
+}
+
+impl DuplicateTracker {
+    fn add(&mut self, value: Option<String>, offset: u64) {
+        let Some(value) = value else {
+            self.without_header += 1;
+            return;
+        };
+        if let Some(group) = self.groups.get_mut(&value) {
+            group.count += 1;
+            if group.offsets.len() < MAX_DUPLICATE_OFFSETS {
+                group.offsets.push(offset);
             }
+        } else if self.groups.len() < MAX_DUPLICATE_GROUPS {
+            self.groups.insert(
+                value.clone(),
+                DuplicateGroup {
+                    value,
+                    count: 1,
+                    offsets: vec![offset],
+                },
+            );
+        } else {
+            self.truncated = true;
         }
-        None => match (from, to) {
-            (None, None) => None,
-            _ => Some(false),
+    }
+
+    fn into_report(self, header: &str) -> DuplicateReport {
+        let mut groups: Vec<DuplicateGroup> = self
+            .groups
+            .into_values()
+            .filter(|group| group.count > 1)
+            .collect();
+        groups.sort_by_key(|group| group.offsets.first().copied());
+        DuplicateReport {
+            header: header.to_string(),
+            groups,
+            without_header: self.without_header,
+            truncated: self.truncated,
+        }
+    }
+}
+
+//groups the messages matching the query by the first configured transaction header and reports
+//every value that was published more than once
+pub async fn find_duplicates(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    message_query: MessageQuery,
+) -> Result<DuplicateReport> {
+    let Some(header) = message_options
+        .transaction_headers
+        .first()
+        .map(String::as_str)
+    else {
+        return Err(ReplayError::TransactionHeaderNotConfigured.into());
+    };
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
+
+    let mut tracker = DuplicateTracker::default();
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "find_duplicates",
+        consumer_args,
+        message_query.from,
+        None,
+        is_match,
+        |delivery, offset| {
+            let value = delivery
+                .properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| TransactionHeader::lookup(headers, header))
+                .map(|transaction| transaction.value);
+            tracker.add(value, u64::try_from(offset)?);
+            Ok(())
         },
+    )
+    .await?;
+    Ok(tracker.into_report(header))
+}
+
+//the number of messages matching a query and the offsets of the first and last of them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageCount {
+    pub queue: String,
+    pub count: u64,
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+}
+
+impl MessageCount {
+    fn add(&mut self, offset: i64) {
+        let offset = u64::try_from(offset).ok();
+        self.count += 1;
+        if self.count == 1 {
+            self.first_offset = offset;
+        }
+        self.last_offset = offset;
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use chrono::{TimeZone, Utc};
+//counts the messages `fetch_messages` would return without decoding them. A query without any
+//filter is answered from the boundaries of the stream instead of consuming it
+#[tracing::instrument(skip_all, fields(
+    queue = %message_query.queue,
+    from = ?message_query.from,
+    to = ?message_query.to,
+    scanned = tracing::field::Empty,
+    matched = tracing::field::Empty,
+))]
+pub async fn count_messages(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    message_query: MessageQuery,
+) -> Result<MessageCount> {
+    if message_query.is_unfiltered() {
+        let offsets = stream_offsets(
+            pool,
+            rabbitmq_api_config,
+            message_options,
+            &message_query.queue,
+        )
+        .await?;
+        return Ok(MessageCount {
+            queue: offsets.queue,
+            count: offsets.messages,
+            first_offset: offsets.first_offset,
+            last_offset: offsets.last_offset,
+        });
+    }
+    let is_match = message_query_matcher(&message_query, message_options)?;
+    let consumer_args = consumer_args_table(message_query.consumer_args.as_ref())?;
+
+    let mut count = MessageCount {
+        queue: message_query.queue.clone(),
+        count: 0,
+        first_offset: None,
+        last_offset: None,
+    };
+    consume_stream(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        &message_query.queue,
+        "count_messages",
+        consumer_args,
+        message_query.from,
+        None,
+        is_match,
+        |_, offset| {
+            count.add(offset);
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(count)
+}
+
+//what a replay would publish, collected without keeping the messages around
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReplayPreview {
+    pub matched_count: u64,
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+    pub first_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    //sum of the message bodies, headers and properties are not counted
+    pub estimated_bytes: u64,
+    //last offset of the stream before the preview was collected. Passed back as
+    //`expected_last_offset`, the replay is refused if messages were added since
+    pub stream_last_offset: Option<u64>,
+}
+
+impl ReplayPreview {
+    fn add(&mut self, delivery: &Delivery, offset: i64) {
+        let offset = u64::try_from(offset).ok();
+        let timestamp = (*delivery.properties.timestamp())
+            .and_then(|timestamp| Utc.timestamp_millis_opt(timestamp as i64).single());
+
+        self.matched_count += 1;
+        if self.matched_count == 1 {
+            self.first_offset = offset;
+            self.first_timestamp = timestamp;
+        }
+        self.last_offset = offset;
+        self.last_timestamp = timestamp;
+        self.estimated_bytes += delivery.data.len() as u64;
+    }
+}
+
+//runs the same consume loop as the replay of the given mode, but only counts the matches
+pub async fn preview_replay(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    replay_mode: &ReplayMode,
+) -> Result<ReplayPreview> {
+    let stream_last_offset = stream_offsets(
+        pool,
+        rabbitmq_api_config,
+        message_options,
+        replay_mode.queue(),
+    )
+    .await?
+    .last_offset;
+    let mut preview = ReplayPreview {
+        stream_last_offset,
+        ..Default::default()
+    };
+    let mut visit = |delivery: Delivery, offset: i64| -> Result<()> {
+        preview.add(&delivery, offset);
+        Ok(())
+    };
+    match replay_mode {
+        ReplayMode::TimeFrameReplay(time_frame) => {
+            consume_stream(
+                pool,
+                rabbitmq_api_config,
+                message_options,
+                &time_frame.queue,
+                "replay_preview",
+                consumer_args_table(time_frame.consumer_args.as_ref())?,
+                Some(time_frame.from),
+                None,
+                time_frame_matcher(time_frame)?,
+                visit,
+            )
+            .await?;
+        }
+        ReplayMode::HeaderReplay(header_replay) => {
+            consume_stream(
+                pool,
+                rabbitmq_api_config,
+                message_options,
+                &header_replay.queue,
+                "replay_preview",
+                consumer_args_table(header_replay.consumer_args.as_ref())?,
+                None,
+                None,
+                header_matcher(header_replay)?,
+                visit,
+            )
+            .await?;
+        }
+        ReplayMode::OffsetReplay(offset_replay) => {
+            for delivery in replay_offset(
+                pool,
+                rabbitmq_api_config,
+                message_options,
+                offset_replay.clone(),
+                None,
+            )
+            .await?
+            {
+                let offset = stream_offset(&delivery)?;
+                visit(delivery, offset)?;
+            }
+        }
+        ReplayMode::FilteredReplay(filtered) => {
+            consume_stream(
+                pool,
+                rabbitmq_api_config,
+                message_options,
+                &filtered.queue,
+                "replay_preview",
+                FieldTable::default(),
+                filtered.from,
+                None,
+                filtered_matcher(filtered)?,
+                visit,
+            )
+            .await?;
+        }
+        ReplayMode::TransactionReplay(transaction) => {
+            consume_stream(
+                pool,
+                rabbitmq_api_config,
+                message_options,
+                &transaction.queue,
+                "replay_preview",
+                FieldTable::default(),
+                None,
+                None,
+                header_matcher(&transaction.header_replay(message_options)?)?,
+                visit,
+            )
+            .await?;
+        }
+    }
+    Ok(preview)
+}
+
+//offsets before the first one of the stream were already truncated by the retention
+fn offset_range_len(
+    offset_replay: &OffsetReplay,
+    first_offset: Option<u64>,
+    last_offset: Option<u64>,
+) -> u64 {
+    let (Some(first_offset), Some(last_offset)) = (first_offset, last_offset) else {
+        return 0;
+    };
+    let from_offset = offset_replay.from_offset.max(first_offset);
+    let to_offset = offset_replay
+        .to_offset
+        .map_or(last_offset, |to_offset| to_offset.min(last_offset));
+    (to_offset + 1).saturating_sub(from_offset)
+}
+
+//returns the offset every active consumer of the stream was attached at together with its
+//distance to the last offset of the stream
+pub async fn consumer_offsets(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    queue: &str,
+) -> Result<Vec<ConsumerOffset>> {
+    let details = get_queue_details(rabbitmq_api_config, queue).await?;
+    let offsets = stream_offsets(pool, rabbitmq_api_config, message_options, queue).await?;
+    Ok(parse_consumer_offsets(&details, offsets.last_offset))
+}
+
+//reads `consumer_details[].arguments["x-stream-offset"]` from the management API response.
+//consumers attached with a non numeric offset spec (first, last, next, a timestamp) are skipped
+pub fn parse_consumer_offsets(
+    details: &serde_json::Value,
+    max_offset: Option<u64>,
+) -> Vec<ConsumerOffset> {
+    let consumers = match details.get("consumer_details").and_then(|c| c.as_array()) {
+        Some(consumers) => consumers,
+        None => return Vec::new(),
+    };
+
+    consumers
+        .iter()
+        .filter_map(|consumer| {
+            let consumer_tag = consumer.get("consumer_tag")?.as_str()?;
+            let current_offset = consumer
+                .get("arguments")?
+                .get("x-stream-offset")?
+                .as_u64()?;
+            Some(ConsumerOffset {
+                consumer_tag: consumer_tag.to_string(),
+                current_offset,
+                lag: max_offset.unwrap_or(0).saturating_sub(current_offset),
+            })
+        })
+        .collect()
+}
+
+//a stream of the vhost, the offsets are `None` for an empty stream or if they couldn't be probed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StreamQueue {
+    pub name: String,
+    pub messages: u64,
+    pub first_offset: Option<u64>,
+    pub last_offset: Option<u64>,
+}
+
+//lists the streams of the vhost accepted by `include` with their message count and offsets,
+//queues of other types can't be read by offset and are left out
+pub async fn list_streams(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    include: impl Fn(&str) -> bool,
+) -> Result<Vec<StreamQueue>> {
+    let idle_timeout = message_options.consumer_idle_timeout;
+    let probes = list_queues(rabbitmq_api_config)
+        .await?
+        .into_iter()
+        .filter(|queue| queue.queue_type.as_deref() == Some("stream") && include(&queue.name))
+        .map(|queue| async move {
+            let messages = queue.messages.unwrap_or(0);
+            //one stream that can't be consumed doesn't hide the others
+            let (first_offset, last_offset) =
+                match probe_stream_offsets(pool, &queue.name, messages, idle_timeout).await {
+                    Ok(offsets) => (offsets.first_offset, offsets.last_offset),
+                    Err(err) => {
+                        tracing::warn!("could not probe the offsets of {}: {:#}", queue.name, err);
+                        (None, None)
+                    }
+                };
+            StreamQueue {
+                name: queue.name,
+                messages,
+                first_offset,
+                last_offset,
+            }
+        });
+
+    //every probe holds a connection of the pool, the streams keep the order of the listing
+    let mut probes = futures::StreamExt::buffered(stream::iter(probes), LIST_STREAMS_CONCURRENCY);
+    let mut streams = Vec::new();
+    while let Some(stream) = probes.next().await {
+        streams.push(stream);
+    }
+    Ok(streams)
+}
+
+//how many streams `list_streams` probes at once
+pub const LIST_STREAMS_CONCURRENCY: usize = 8;
+
+//looks up the boundaries of the stream. The message count is taken from the management API,
+//the first and last offset are probed by consuming a single message at each end of the stream.
+pub async fn stream_offsets(
+    pool: &AmqpPool,
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    message_options: &MessageOptions,
+    queue: &str,
+) -> Result<StreamOffsets> {
+    let message_count = match get_queue_message_count(rabbitmq_api_config, queue).await? {
+        Some(message_count) => message_count,
+        None => return Err(anyhow!("Queue not found")),
+    };
+    probe_stream_offsets(
+        pool,
+        queue,
+        message_count,
+        message_options.consumer_idle_timeout,
+    )
+    .await
+}
+
+//the first and last offset of a stream holding `message_count` messages
+async fn probe_stream_offsets(
+    pool: &AmqpPool,
+    queue: &str,
+    message_count: u64,
+    idle_timeout: std::time::Duration,
+) -> Result<StreamOffsets> {
+    let mut offsets = StreamOffsets {
+        queue: queue.to_string(),
+        messages: message_count,
+        first_offset: None,
+        last_offset: None,
+        first_timestamp: None,
+        last_timestamp: None,
+    };
+
+    if message_count == 0 {
+        return Ok(offsets);
+    }
+
+    let connection = pool.get().await?;
+    let channel = connection.create_channel().await?;
+
+    channel
+        .basic_qos(1u16, BasicQosOptions { global: false })
+        .await?;
+
+    let (first_offset, first_timestamp) = probe_offset(
+        &channel,
+        queue,
+        AMQPValue::LongString("first".into()),
+        0,
+        idle_timeout,
+    )
+    .await?;
+    let last_offset = first_offset + message_count - 1;
+    let (last_offset, last_timestamp) = probe_offset(
+        &channel,
+        queue,
+        AMQPValue::LongLongInt(i64::try_from(last_offset)?),
+        last_offset,
+        idle_timeout,
+    )
+    .await?;
+
+    offsets.first_offset = Some(first_offset);
+    offsets.last_offset = Some(last_offset);
+    offsets.first_timestamp = first_timestamp;
+    offsets.last_timestamp = last_timestamp;
+    Ok(offsets)
+}
+
+//consumes from the given stream offset until a message with an offset of at least `min_offset`
+//is delivered and returns its offset and timestamp
+async fn probe_offset(
+    channel: &lapin::Channel,
+    queue: &str,
+    stream_offset: AMQPValue,
+    min_offset: u64,
+    idle_timeout: std::time::Duration,
+) -> Result<(u64, Option<chrono::DateTime<chrono::Utc>>)> {
+    let mut consumer = channel
+        .basic_consume(
+            queue,
+            "probe_offset",
+            BasicConsumeOptions::default(),
+            stream_consume_args(stream_offset, FieldTable::default()),
+        )
+        .await?;
+
+    while let Some(delivery) = next_delivery(&mut consumer, idle_timeout).await {
+        delivery.ack(BasicAckOptions::default()).await?;
+        let offset = stream_offset(&delivery)? as u64;
+        if offset < min_offset {
+            continue;
+        }
+        channel
+            .basic_cancel(consumer.tag().as_str(), BasicCancelOptions::default())
+            .await?;
+        let timestamp = delivery
+            .properties
+            .timestamp()
+            .map(|timestamp| Utc.timestamp_millis_opt(timestamp as i64).unwrap());
+        return Ok((offset, timestamp));
+    }
+    Err(anyhow!(
+        "Consumer closed before offset {} of queue {} was reached",
+        min_offset,
+        queue
+    ))
+}
+
+//points the messages at the default exchange with the queue as routing key, so publishing them
+//delivers them to that queue only instead of routing them through their original exchange
+pub fn route_to_queue(messages: &mut [Delivery], queue: &str) {
+    for message in messages {
+        message.exchange = "".into();
+        message.routing_key = queue.into();
+    }
+}
+
+//upper bound for `delay_ms` of a replay request
+pub const MAX_REPLAY_DELAY_MS: u64 = 15 * 60 * 1000;
+
+//how the delay of a replay is applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayStrategy {
+    //every target exchange is an `x-delayed-message` exchange, messages are published with
+    //an `x-delay` header and held back by the broker
+    Header(u64),
+    //the start of the publish loop is held back by the delay
+    Hold(u64),
+}
+
+//holds back every publish of a paced replay by the gap between the timestamp of the message and
+//the one before, divided by the speed. The pauses add up to at most `max_duration`, messages left
+//after that are published right away.
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    speed: f64,
+    remaining: std::time::Duration,
+    previous_timestamp: Option<u64>,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing, max_duration: std::time::Duration) -> Self {
+        Self {
+            speed: pacing.speed(),
+            remaining: max_duration,
+            previous_timestamp: None,
+        }
+    }
+
+    //messages without a timestamp are published right away and leave the previous timestamp
+    //as it is. Timestamps going backwards don't pause.
+    fn pause(&mut self, timestamp: Option<u64>) -> std::time::Duration {
+        let timestamp = match timestamp {
+            Some(timestamp) => timestamp,
+            None => return std::time::Duration::ZERO,
+        };
+        let gap_ms = match self.previous_timestamp.replace(timestamp) {
+            Some(previous) => timestamp.saturating_sub(previous),
+            None => return std::time::Duration::ZERO,
+        };
+        let pause = std::time::Duration::try_from_secs_f64(gap_ms as f64 / 1000.0 / self.speed)
+            .unwrap_or(std::time::Duration::MAX)
+            .min(self.remaining);
+        self.remaining -= pause;
+        pause
+    }
+
+    async fn wait(&mut self, message: &Delivery) {
+        let pause = self.pause(*message.properties.timestamp());
+        if !pause.is_zero() {
+            tokio::time::sleep(pause).await;
+        }
+    }
+}
+
+//lets a replay publish at most `rate_limit` messages per second. A token bucket that holds a
+//single token, a publish that took longer doesn't earn the replay a burst afterwards.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    interval: std::time::Duration,
+    next: Option<tokio::time::Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit: u32) -> Self {
+        Self {
+            interval: std::time::Duration::from_secs(1) / rate_limit.max(1),
+            next: None,
+        }
+    }
+
+    //the time until the next token at `now`, which is taken right away
+    fn pause(&mut self, now: tokio::time::Instant) -> std::time::Duration {
+        let at = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(at + self.interval);
+        at - now
+    }
+
+    async fn wait(&mut self) {
+        let pause = self.pause(tokio::time::Instant::now());
+        if !pause.is_zero() {
+            tokio::time::sleep(pause).await;
+        }
+    }
+}
+
+//uses the `x-delay` header if all exchanges the messages are replayed to support it, any
+//other exchange would route the messages right away
+pub async fn delay_strategy(
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    messages: &[Delivery],
+    delay_ms: u64,
+) -> Result<DelayStrategy> {
+    let exchanges: BTreeSet<&str> = messages
+        .iter()
+        .map(|message| message.exchange.as_str())
+        .collect();
+    exchanges_delay_strategy(rabbitmq_api_config, exchanges, delay_ms).await
+}
+
+//same as `delay_strategy` for a fan-out replay, which publishes to its targets instead
+pub async fn targets_delay_strategy(
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    targets: &[ReplayTarget],
+    delay_ms: u64,
+) -> Result<DelayStrategy> {
+    let exchanges: BTreeSet<&str> = targets
+        .iter()
+        .map(|target| target.exchange.as_str())
+        .collect();
+    exchanges_delay_strategy(rabbitmq_api_config, exchanges, delay_ms).await
+}
+
+async fn exchanges_delay_strategy(
+    rabbitmq_api_config: &RabbitmqApiConfig,
+    exchanges: BTreeSet<&str>,
+    delay_ms: u64,
+) -> Result<DelayStrategy> {
+    let mut delayed = Vec::new();
+    for exchange in exchanges {
+        //the default exchange can't be redeclared with another type
+        let is_delayed = !exchange.is_empty()
+            && get_exchange_type(rabbitmq_api_config, exchange)
+                .await?
+                .as_deref()
+                == Some(DELAYED_EXCHANGE_TYPE);
+        delayed.push(is_delayed);
+    }
+    Ok(choose_delay_strategy(&delayed, delay_ms))
+}
+
+const DELAYED_EXCHANGE_TYPE: &str = "x-delayed-message";
+
+fn choose_delay_strategy(delayed_exchanges: &[bool], delay_ms: u64) -> DelayStrategy {
+    if !delayed_exchanges.is_empty() && delayed_exchanges.iter().all(|delayed| *delayed) {
+        DelayStrategy::Header(delay_ms)
+    } else {
+        DelayStrategy::Hold(delay_ms)
+    }
+}
+
+fn with_delay_header(properties: lapin::BasicProperties, delay_ms: u64) -> lapin::BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    headers.insert(
+        ShortString::from("x-delay"),
+        AMQPValue::LongLongInt(i64::try_from(delay_ms).unwrap_or(i64::MAX)),
+    );
+    properties.with_headers(headers)
+}
+
+//the properties of a replayed message: those of the consumed message with a new timestamp if
+//enabled, a new id or the requested `transaction_value` for every configured transaction header,
+//the provenance headers and the `x-delay` header if the target exchange delays the message.
+//The offset the stream added on delivery is dropped, it is carried over in
+//`x-replayed-from-offset`
+fn replay_properties(
+    properties: &lapin::BasicProperties,
+    message_options: &MessageOptions,
+    transaction_value: Option<&str>,
+    delay: Option<DelayStrategy>,
+) -> (
+    lapin::BasicProperties,
+    Vec<TransactionHeader>,
+    Option<chrono::DateTime<chrono::Utc>>,
+) {
+    let replayed_at = chrono::Utc::now();
+    let new_timestamp = message_options.enable_timestamp.then_some(replayed_at);
+    let timestamp = new_timestamp.or_else(|| {
+        properties
+            .timestamp()
+            .and_then(|timestamp| Utc.timestamp_millis_opt(timestamp as i64).single())
+    });
+    let transactions: Vec<TransactionHeader> = message_options
+        .transaction_headers
+        .iter()
+        .map(|transaction_header| TransactionHeader {
+            name: transaction_header.clone(),
+            value: transaction_value
+                .map(str::to_string)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        })
+        .collect();
+
+    let basic_props = match new_timestamp {
+        Some(timestamp) => properties
+            .clone()
+            .with_timestamp(timestamp.timestamp_millis() as u64),
+        None => properties.clone(),
+    };
+    let original = properties.headers().as_ref().map(FieldTable::inner);
+    let mut headers: BTreeMap<ShortString, AMQPValue> = original
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| name.as_str() != "x-stream-offset")
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    for transaction in &transactions {
+        headers.insert(
+            ShortString::from(transaction.name.as_str()),
+            AMQPValue::LongString(transaction.value.as_str().into()),
+        );
+    }
+    //a replayed copy being replayed again points to the copy it was read from
+    if let Some(offset) = original.and_then(|headers| headers.get("x-stream-offset")) {
+        headers.insert(
+            ShortString::from(REPLAYED_FROM_OFFSET_HEADER),
+            offset.clone(),
+        );
+    }
+    if let Some(original_timestamp) = *properties.timestamp() {
+        headers.insert(
+            ShortString::from(REPLAYED_ORIGINAL_TIMESTAMP_HEADER),
+            AMQPValue::LongLongInt(i64::try_from(original_timestamp).unwrap_or(i64::MAX)),
+        );
+    }
+    headers.insert(
+        ShortString::from(REPLAYED_AT_HEADER),
+        AMQPValue::LongLongInt(replayed_at.timestamp_millis()),
+    );
+    let basic_props = basic_props.with_headers(headers.into());
+    let basic_props = match delay {
+        Some(DelayStrategy::Header(delay_ms)) => with_delay_header(basic_props, delay_ms),
+        _ => basic_props,
+    };
+    let basic_props = if message_options.publish_persistent {
+        basic_props.with_delivery_mode(2)
+    } else {
+        basic_props
+    };
+    (basic_props, transactions, timestamp)
+}
+
+//publishes the given messages, messages can be published with or without
+//transaction- and timestamp headers depending on the environment variables set.
+#[tracing::instrument(skip_all, fields(
+    messages = messages.len(),
+    published = tracing::field::Empty,
+))]
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_message(
+    pool: &AmqpPool,
+    message_options: &MessageOptions,
+    queue: &str,
+    transaction_value: Option<&str>,
+    messages: Vec<Delivery>,
+    delay: Option<DelayStrategy>,
+    mut pacer: Option<Pacer>,
+    mut rate_limiter: Option<RateLimiter>,
+) -> Result<Vec<Message>> {
+    if let Some(DelayStrategy::Hold(delay_ms)) = delay {
+        tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+    }
+
+    //the connection stays out of the pool until the last message is published, for as long as
+    //the pauses of a paced or rate limited replay add up to
+    let connection = pool.get().await?;
+    //every message is confirmed before the next one is published, a message the broker didn't
+    //take over fails the replay instead of being listed as replayed
+    let channel = confirm_channel(&connection).await?;
+    let mut progress = Progress::new("publish", queue, message_options.progress_log);
+    let mut s = stream::iter(messages);
+    let mut replayed_messages = Vec::new();
+
+    while let Some(message) = s.next().await {
+        if let Some(pacer) = &mut pacer {
+            pacer.wait(&message).await;
+        }
+        if let Some(rate_limiter) = &mut rate_limiter {
+            rate_limiter.wait().await;
+        }
+        let (basic_props, transactions, timestamp) = replay_properties(
+            &message.properties,
+            message_options,
+            transaction_value,
+            delay,
+        );
+        let replayed = Provenance::from_properties(&basic_props);
+
+        let not_confirmed =
+            |reason: String| ReplayError::PublishNotConfirmed(replayed_messages.len(), reason);
+        let confirmation = async {
+            channel
+                .basic_publish(
+                    message.exchange.as_str(),
+                    message.routing_key.as_str(),
+                    BasicPublishOptions {
+                        mandatory: message_options.publish_mandatory,
+                        ..Default::default()
+                    },
+                    message.data.as_slice(),
+                    basic_props,
+                )
+                .await?
+                .await
+        }
+        .await
+        .map_err(|err: lapin::Error| not_confirmed(err.to_string()))?;
+
+        if confirmation.is_nack() {
+            return Err(not_confirmed("Message was nacked by the broker".to_string()).into());
+        }
+        if let Some(returned) = confirmation.take_message() {
+            return Err(anyhow!(
+                "Message to exchange {:?} with routing key {:?} was returned as unroutable: {}",
+                returned.delivery.exchange.as_str(),
+                returned.delivery.routing_key.as_str(),
+                returned.reply_text.as_str()
+            ));
+        }
+        progress.published(stream_offset(&message).ok());
+
+        let published_to = Some(ReplayTarget {
+            exchange: message.exchange.to_string(),
+            routing_key: message.routing_key.to_string(),
+        });
+        let size_bytes = message.data.len();
+        let (encoding, data) = PayloadEncoding::encode(message.data, false);
+        replayed_messages.push(Message {
+            offset: None,
+            transactions,
+            app_id: None,
+            published_to,
+            replayed,
+            timestamp,
+            size_bytes,
+            encoding,
+            data,
+        });
+    }
+    Ok(replayed_messages)
+}
+
+//upper bound for the targets of a fan-out replay, every target gets its own channel
+pub const MAX_REPLAY_TARGETS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TargetSummary {
+    pub exchange: String,
+    pub routing_key: String,
+    pub published: u64,
+    pub failed: u64,
+    //the first failure of the target, later messages usually fail for the same reason
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FanOutReplay {
+    pub messages: Vec<Message>,
+    pub targets: Vec<TargetSummary>,
+}
+
+impl FanOutReplay {
+    pub fn has_failures(&self) -> bool {
+        self.targets.iter().any(|target| target.failed > 0)
+    }
+}
+
+//publishes every message to each of the targets. A target that fails doesn't stop the replay,
+//its failures are counted in its summary instead. The messages carry the same transaction id
+//and timestamp on every target.
+#[allow(clippy::too_many_arguments)]
+pub async fn publish_to_targets(
+    pool: &AmqpPool,
+    message_options: &MessageOptions,
+    queue: &str,
+    transaction_value: Option<&str>,
+    messages: Vec<Delivery>,
+    targets: &[ReplayTarget],
+    delay: Option<DelayStrategy>,
+    mut pacer: Option<Pacer>,
+    mut rate_limiter: Option<RateLimiter>,
+) -> Result<FanOutReplay> {
+    if let Some(DelayStrategy::Hold(delay_ms)) = delay {
+        tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+    }
+
+    let connection = pool.get().await?;
+    let mut channels = Vec::new();
+    let mut summaries = Vec::new();
+    for target in targets {
+        let mut summary = TargetSummary {
+            exchange: target.exchange.clone(),
+            routing_key: target.routing_key.clone(),
+            published: 0,
+            failed: 0,
+            error: None,
+        };
+        //the broker closes the channel of a publish to a missing exchange, a channel per
+        //target keeps the other targets going
+        let channel = match confirm_channel(&connection).await {
+            Ok(channel) => Some(channel),
+            Err(err) => {
+                summary.error = Some(err.to_string());
+                None
+            }
+        };
+        channels.push(channel);
+        summaries.push(summary);
+    }
+
+    let mut progress = Progress::new("publish", queue, message_options.progress_log);
+    let mut replayed_messages = Vec::new();
+    for message in messages {
+        if let Some(pacer) = &mut pacer {
+            pacer.wait(&message).await;
+        }
+        if let Some(rate_limiter) = &mut rate_limiter {
+            rate_limiter.wait().await;
+        }
+        let (basic_props, transactions, timestamp) = replay_properties(
+            &message.properties,
+            message_options,
+            transaction_value,
+            delay,
+        );
+        let replayed = Provenance::from_properties(&basic_props);
+        //a message published to several targets is named in their summaries instead
+        let mut published_to = None;
+        for ((target, channel), summary) in targets.iter().zip(&channels).zip(&mut summaries) {
+            let result = match channel {
+                Some(channel) => {
+                    publish_confirmed(
+                        channel,
+                        target,
+                        message_options.publish_mandatory,
+                        &message.data,
+                        basic_props.clone(),
+                    )
+                    .await
+                }
+                None => Err(anyhow!("no channel to the target")),
+            };
+            match result {
+                Ok(()) => {
+                    summary.published += 1;
+                    if targets.len() == 1 {
+                        published_to = Some(target.clone());
+                    }
+                }
+                Err(err) => {
+                    summary.failed += 1;
+                    summary.error.get_or_insert(err.to_string());
+                }
+            }
+        }
+        progress.published(stream_offset(&message).ok());
+
+        let size_bytes = message.data.len();
+        let (encoding, data) = PayloadEncoding::encode(message.data, false);
+        replayed_messages.push(Message {
+            offset: None,
+            transactions,
+            app_id: None,
+            published_to,
+            replayed,
+            timestamp,
+            size_bytes,
+            encoding,
+            data,
+        });
+    }
+    Ok(FanOutReplay {
+        messages: replayed_messages,
+        targets: summaries,
+    })
+}
+
+async fn confirm_channel(connection: &lapin::Connection) -> Result<lapin::Channel> {
+    let channel = connection.create_channel().await?;
+    channel
+        .confirm_select(ConfirmSelectOptions::default())
+        .await?;
+    Ok(channel)
+}
+
+async fn publish_confirmed(
+    channel: &lapin::Channel,
+    target: &ReplayTarget,
+    mandatory: bool,
+    data: &[u8],
+    properties: lapin::BasicProperties,
+) -> Result<()> {
+    let confirmation = channel
+        .basic_publish(
+            &target.exchange,
+            &target.routing_key,
+            BasicPublishOptions {
+                mandatory,
+                ..Default::default()
+            },
+            data,
+            properties,
+        )
+        .await?
+        .await?;
+    if confirmation.is_nack() {
+        return Err(anyhow!("Message was nacked by the broker"));
+    }
+    if let Some(returned) = confirmation.take_message() {
+        return Err(anyhow!(
+            "Message was returned as unroutable: {}",
+            returned.reply_text.as_str()
+        ));
+    }
+    Ok(())
+}
+
+//writes the messages to a new file at the given path, one JSON document per line
+pub async fn write_ndjson(path: &std::path::Path, messages: &[Message]) -> Result<()> {
+    let file = tokio::fs::File::create(path).await?;
+    let mut writer = tokio::io::BufWriter::new(file);
+    for message in messages {
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+//resolves a header name to its value. Dotted names like `meta.tenant` descend into table
+//valued headers, a literal dot in a header name can be escaped as `\.`
+pub fn lookup_header<'a>(headers: &'a FieldTable, name: &str) -> Option<&'a AMQPValue> {
+    lookup_header_path(headers, &header_path(name))
+}
+
+//splits a header name into the keys used by `lookup_header_path`, so loops over many
+//deliveries only have to parse the name once
+pub fn header_path(name: &str) -> Vec<ShortString> {
+    split_header_path(name)
+        .into_iter()
+        .map(ShortString::from)
+        .collect()
+}
+
+pub fn lookup_header_path<'a>(
+    headers: &'a FieldTable,
+    path: &[ShortString],
+) -> Option<&'a AMQPValue> {
+    let (first, rest) = path.split_first()?;
+    let mut value = headers.inner().get(first)?;
+    for segment in rest {
+        value = match value {
+            AMQPValue::FieldTable(table) => table.inner().get(segment)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+fn split_header_path(name: &str) -> Vec<String> {
+    let mut segments = vec![String::new()];
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or('\\');
+                segments.last_mut().unwrap().push(escaped);
+            }
+            '.' => segments.push(String::new()),
+            c => segments.last_mut().unwrap().push(c),
+        }
+    }
+    segments
+}
+
+//reads the offset RabbitMQ attaches to every message consumed from a stream
+fn stream_offset(delivery: &Delivery) -> Result<i64> {
+    let offset = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get("x-stream-offset"));
+    match offset {
+        Some(AMQPValue::LongLongInt(offset)) => Ok(*offset),
+        _ => Err(anyhow!(
+            "x-stream-offset header not found on message, only stream queues can be read"
+        )),
+    }
+}
+
+//true once the consumer has reached the last message counted by the management api
+fn is_last_offset(offset: i64, message_count: u64) -> bool {
+    match u64::try_from(offset) {
+        Ok(offset) => offset.saturating_add(1) >= message_count,
+        Err(_) => false,
+    }
+}
+
+//how long a consumer waits for the next delivery before it gives up. Without it a consumer
+//whose broker stops delivering, e.g. after a network partition, or a scan waiting for an offset
+//promised by a stale message count would block forever.
+pub const DEFAULT_CONSUMER_IDLE_TIMEOUT_MS: u64 = 5000;
+
+//returns the next delivery, or None once the consumer is closed, fails or waited `timeout` for
+//it, so callers end their loop with what they collected so far
+async fn next_delivery(
+    consumer: &mut lapin::Consumer,
+    timeout: std::time::Duration,
+) -> Option<Delivery> {
+    match tokio::time::timeout(timeout, consumer.next()).await {
+        Ok(Some(Ok(delivery))) => Some(delivery),
+        Ok(_) => None,
+        Err(_) => {
+            tracing::warn!(
+                "no delivery on consumer {} within {} ms, stopping with what was read so far",
+                consumer.tag().as_str(),
+                timeout.as_millis()
+            );
+            None
+        }
+    }
+}
+
+//how long before the start of a time frame a scan seeks to. The stream seeks by the time a
+//chunk was written, a message stamped by a publisher whose clock is ahead of the broker's can
+//be written before the time on it
+pub const STREAM_SEEK_MARGIN_SECS: i64 = 60;
+
+//where a scan attaches to the stream. Seeking by timestamp lands on the chunk boundary before
+//`seek_to`, so the timestamp of every delivered message still has to be checked
+fn stream_start(seek_to: Option<chrono::DateTime<Utc>>) -> AMQPValue {
+    match seek_to {
+        Some(seek_to) => AMQPValue::Timestamp(
+            u64::try_from(seek_to.timestamp() - STREAM_SEEK_MARGIN_SECS).unwrap_or(0),
+        ),
+        None => AMQPValue::LongString("first".into()),
+    }
+}
+
+//the offset always wins over a x-stream-offset given in the consumer arguments
+fn stream_consume_args(stream_offset: AMQPValue, consumer_args: FieldTable) -> FieldTable {
+    let mut args = consumer_args;
+    args.insert(ShortString::from("x-stream-offset"), stream_offset);
+    args
+}
+
+//how often consume and publish loops log their progress
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProgressLog {
+    //0 disables the count based progress events
+    pub every_messages: u64,
+    pub interval: std::time::Duration,
+}
+
+impl ProgressLog {
+    pub const DEFAULT: ProgressLog = ProgressLog {
+        every_messages: 10_000,
+        interval: std::time::Duration::from_secs(30),
+    };
+}
+
+//what an asynchronous replay job got through so far, summed over all of its loops
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct JobCounts {
+    pub scanned: u64,
+    pub matched: u64,
+    pub published: u64,
+    pub last_offset: Option<i64>,
+}
+
+//shared between the job registry and the task running the job
+#[derive(Debug, Default)]
+pub struct JobProgress(std::sync::Mutex<JobCounts>);
+
+impl JobProgress {
+    pub fn counts(&self) -> JobCounts {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn update(&self, update: impl FnOnce(&mut JobCounts)) {
+        update(&mut self.0.lock().unwrap_or_else(|err| err.into_inner()));
+    }
+}
+
+tokio::task_local! {
+    static JOB_PROGRESS: std::sync::Arc<JobProgress>;
+}
+
+//every loop run by `future` also counts its work in `progress`. The loops don't spawn tasks of
+//their own, so the task local reaches all of them
+pub async fn track_job_progress<F: std::future::Future>(
+    progress: std::sync::Arc<JobProgress>,
+    future: F,
+) -> F::Output {
+    JOB_PROGRESS.scope(progress, future).await
+}
+
+//outside of `track_job_progress` nothing is tracked
+fn update_job_progress(update: impl FnOnce(&mut JobCounts)) {
+    let _ = JOB_PROGRESS.try_with(|progress| progress.update(update));
+}
+
+//counts the work of a consume or publish loop. The counters are logged as progress events and
+//exported as metrics from the same place, so the logs and the metrics always agree.
+//dropping it exports the remaining counts and logs a summary, also if the loop failed
+struct Progress {
+    operation: String,
+    queue: String,
+    scanned: u64,
+    matched: u64,
+    published: u64,
+    current_offset: Option<i64>,
+    started: std::time::Instant,
+    last_logged: std::time::Instant,
+    last_logged_count: u64,
+    //counters already added to the metrics
+    exported: (u64, u64, u64),
+    log: ProgressLog,
+}
+
+impl Progress {
+    fn new(operation: &str, queue: &str, log: ProgressLog) -> Self {
+        let now = std::time::Instant::now();
+        Self {
+            operation: operation.to_string(),
+            queue: queue.to_string(),
+            scanned: 0,
+            matched: 0,
+            published: 0,
+            current_offset: None,
+            started: now,
+            last_logged: now,
+            last_logged_count: 0,
+            exported: (0, 0, 0),
+            log,
+        }
+    }
+
+    fn scanned(&mut self, offset: i64, matched: bool) {
+        self.scanned += 1;
+        if matched {
+            self.matched += 1;
+        }
+        self.current_offset = Some(offset);
+        update_job_progress(|job| {
+            job.scanned += 1;
+            job.matched += u64::from(matched);
+            job.last_offset = Some(offset);
+        });
+        self.tick();
+    }
+
+    fn published(&mut self, offset: Option<i64>) {
+        self.published += 1;
+        self.current_offset = offset.or(self.current_offset);
+        update_job_progress(|job| {
+            job.published += 1;
+            job.last_offset = offset.or(job.last_offset);
+        });
+        self.tick();
+    }
+
+    //each loop only advances one of the two counters
+    fn processed(&self) -> u64 {
+        self.scanned + self.published
+    }
+
+    fn tick(&mut self) {
+        let processed = self.processed();
+        let count_due = self.log.every_messages > 0
+            && processed - self.last_logged_count >= self.log.every_messages;
+        if count_due || self.last_logged.elapsed() >= self.log.interval {
+            self.export_metrics();
+            tracing::info!(
+                operation = %self.operation,
+                queue = %self.queue,
+                scanned = self.scanned,
+                matched = self.matched,
+                published = self.published,
+                current_offset = ?self.current_offset,
+                rate = format!("{:.1}/s", self.rate()),
+                "progress"
+            );
+            self.last_logged = std::time::Instant::now();
+            self.last_logged_count = processed;
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.processed() as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn export_metrics(&mut self) {
+        let labels = [
+            ("operation", self.operation.clone()),
+            ("queue", self.queue.clone()),
+        ];
+        let (scanned, matched, published) = self.exported;
+        metrics::counter!(
+            "replay_messages_scanned_total",
+            self.scanned - scanned,
+            &labels
+        );
+        metrics::counter!(
+            "replay_messages_matched_total",
+            self.matched - matched,
+            &labels
+        );
+        metrics::counter!(
+            "replay_messages_published_total",
+            self.published - published,
+            &labels
+        );
+        self.exported = (self.scanned, self.matched, self.published);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.export_metrics();
+        //fills in the counters of the instrumented function the loop runs in, fields the span
+        //doesn't declare are ignored
+        let span = tracing::Span::current();
+        span.record("scanned", self.scanned);
+        span.record("matched", self.matched);
+        span.record("published", self.published);
+        tracing::info!(
+            operation = %self.operation,
+            queue = %self.queue,
+            scanned = self.scanned,
+            matched = self.matched,
+            published = self.published,
+            current_offset = ?self.current_offset,
+            rate = format!("{:.1}/s", self.rate()),
+            elapsed_ms = self.started.elapsed().as_millis() as u64,
+            "finished"
+        );
+    }
+}
+
+const MAX_CONSUMER_ARG_DEPTH: usize = 3;
+
+//converts the consumer arguments of a request to the AMQP table passed to basic.consume
+pub fn consumer_args_table(
+    consumer_args: Option<&ConsumerArgs>,
+) -> Result<FieldTable, ReplayError> {
+    let mut table = FieldTable::default();
+    let consumer_args = match consumer_args {
+        Some(consumer_args) => consumer_args,
+        None => return Ok(table),
+    };
+    for (name, value) in &consumer_args.0 {
+        validate_header_name(name).map_err(|_| {
+            ReplayError::InvalidConsumerArgument(name.clone(), "invalid name".into())
+        })?;
+        let value = json_to_amqp_value(value, 1)
+            .map_err(|reason| ReplayError::InvalidConsumerArgument(name.clone(), reason))?;
+        table.insert(ShortString::from(name.as_str()), value);
+    }
+    Ok(table)
+}
+
+fn json_to_amqp_value(value: &serde_json::Value, depth: usize) -> Result<AMQPValue, String> {
+    if depth > MAX_CONSUMER_ARG_DEPTH {
+        return Err(format!(
+            "values must not be nested deeper than {} levels",
+            MAX_CONSUMER_ARG_DEPTH
+        ));
+    }
+    match value {
+        serde_json::Value::Null => Ok(AMQPValue::Void),
+        serde_json::Value::Bool(value) => Ok(AMQPValue::Boolean(*value)),
+        serde_json::Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(number), _) => Ok(AMQPValue::LongLongInt(number)),
+            (None, Some(_)) if number.is_u64() => Err(format!("{} is out of range", number)),
+            (None, Some(number)) => Ok(AMQPValue::Double(number)),
+            (None, None) => Err(format!("{} is not a supported number", number)),
+        },
+        serde_json::Value::String(value) => Ok(AMQPValue::LongString(value.as_str().into())),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| json_to_amqp_value(value, depth + 1))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|values| AMQPValue::FieldArray(values.into())),
+        serde_json::Value::Object(values) => {
+            let mut table = FieldTable::default();
+            for (name, value) in values {
+                table.insert(
+                    ShortString::from(name.as_str()),
+                    json_to_amqp_value(value, depth + 1)?,
+                );
+            }
+            Ok(AMQPValue::FieldTable(table))
+        }
+    }
+}
+
+fn is_within_timeframe(
+    date: Option<u64>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<bool> {
+    match date {
+        Some(date) => {
+            let date = Utc.timestamp_millis_opt(date as i64).unwrap();
+            match (from, to) {
+                (Some(from), Some(to)) => Some(date >= from && date <= to),
+                (Some(from), None) => Some(date >= from),
+                (None, Some(to)) => Some(date <= to),
+                (None, None) => Some(true),
+            }
+        }
+        None => match (from, to) {
+            (None, None) => None,
+            _ => Some(false),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use lapin::types::{AMQPValue, FieldTable, ShortString};
+
+    fn death_entry(entries: Vec<(&str, AMQPValue)>) -> AMQPValue {
+        let mut entry = FieldTable::default();
+        for (key, value) in entries {
+            entry.insert(ShortString::from(key), value);
+        }
+        AMQPValue::FieldTable(entry)
+    }
+
+    #[tokio::test]
+    async fn test_parse_x_death() {
+        //as written by RabbitMQ 3.12
+        let current = vec![
+            death_entry(vec![
+                ("count", AMQPValue::LongLongInt(3)),
+                ("reason", AMQPValue::LongString("rejected".into())),
+                ("queue", AMQPValue::LongString("orders".into())),
+                ("time", AMQPValue::Timestamp(1697000000)),
+                ("exchange", AMQPValue::LongString("".into())),
+                (
+                    "routing-keys",
+                    AMQPValue::FieldArray(vec![AMQPValue::LongString("orders".into())].into()),
+                ),
+            ]),
+            death_entry(vec![
+                ("count", AMQPValue::LongLongInt(1)),
+                ("reason", AMQPValue::LongString("expired".into())),
+                ("queue", AMQPValue::LongString("orders.retry".into())),
+            ]),
+        ];
+        //older brokers don't set a count, some clients use short strings or other integer types
+        let legacy = vec![
+            death_entry(vec![
+                ("reason", AMQPValue::ShortString("rejected".into())),
+                ("queue", AMQPValue::ShortString("orders".into())),
+            ]),
+            death_entry(vec![
+                ("count", AMQPValue::LongInt(5)),
+                ("reason", AMQPValue::ByteArray(b"maxlen".to_vec().into())),
+                ("queue", AMQPValue::LongString("orders".into())),
+            ]),
+            AMQPValue::LongString("not a table".into()),
+        ];
+
+        let tests = vec![
+            (
+                Some(AMQPValue::FieldArray(current.into())),
+                vec![
+                    (Some("orders"), Some("rejected"), 3),
+                    (Some("orders.retry"), Some("expired"), 1),
+                ],
+            ),
+            (
+                Some(AMQPValue::FieldArray(legacy.into())),
+                vec![
+                    (Some("orders"), Some("rejected"), 1),
+                    (Some("orders"), Some("maxlen"), 5),
+                ],
+            ),
+            (
+                Some(death_entry(vec![(
+                    "reason",
+                    AMQPValue::LongString("expired".into()),
+                )])),
+                vec![(None, Some("expired"), 1)],
+            ),
+            (Some(AMQPValue::LongString("rejected".into())), vec![]),
+            (None, vec![]),
+        ];
+
+        for (x_death, expected) in tests {
+            let mut headers = FieldTable::default();
+            if let Some(x_death) = x_death {
+                headers.insert(ShortString::from("x-death"), x_death);
+            }
+            let expected: Vec<super::DeathRecord> = expected
+                .into_iter()
+                .map(|(queue, reason, count)| super::DeathRecord {
+                    queue: queue.map(String::from),
+                    reason: reason.map(String::from),
+                    count,
+                })
+                .collect();
+            assert_eq!(expected, super::parse_x_death(&headers));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_death_record_matches() {
+        let record = super::DeathRecord {
+            queue: Some("orders".to_string()),
+            reason: Some("rejected".to_string()),
+            count: 3,
+        };
+
+        let tests = vec![
+            ((None, None, None), true),
+            ((Some("rejected"), None, None), true),
+            ((Some("expired"), None, None), false),
+            ((Some("rejected"), Some(3), Some("orders")), true),
+            ((Some("rejected"), Some(4), Some("orders")), false),
+            ((None, Some(2), None), true),
+            ((None, None, Some("payments")), false),
+        ];
+
+        for ((reason, min_count, original_queue), expected) in tests {
+            let filter = crate::DeadLetterFilter {
+                reason: reason.map(String::from),
+                min_count,
+                original_queue: original_queue.map(String::from),
+            };
+            assert_eq!(expected, record.matches(&filter));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority_range() {
+        let tests = vec![
+            ((None, None), Ok(None)),
+            ((Some(8), None), Ok(Some(8..=255))),
+            ((None, Some(3)), Ok(Some(0..=3))),
+            ((Some(2), Some(2)), Ok(Some(2..=2))),
+            ((Some(0), Some(255)), Ok(Some(0..=255))),
+            ((Some(9), Some(8)), Err(())),
+            ((Some(256), None), Err(())),
+            ((None, Some(1000)), Err(())),
+        ];
+
+        for ((min_priority, max_priority), expected) in tests {
+            assert_eq!(
+                expected,
+                super::priority_range(min_priority, max_priority).map_err(|_| ())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_size_range() {
+        let tests = vec![
+            ((None, None), Ok(None)),
+            ((Some(1024), None), Ok(Some(1024..=usize::MAX))),
+            ((None, Some(10)), Ok(Some(0..=10))),
+            ((Some(5), Some(5)), Ok(Some(5..=5))),
+            ((Some(10), Some(9)), Err(())),
+        ];
+
+        for ((min_bytes, max_bytes), expected) in tests {
+            assert_eq!(
+                expected,
+                super::size_range(min_bytes, max_bytes).map_err(|_| ())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_time_range() {
+        let earlier = Utc.with_ymd_and_hms(2023, 10, 6, 13, 0, 0).unwrap();
+        let later = Utc.with_ymd_and_hms(2023, 10, 6, 14, 0, 0).unwrap();
+        let tests = vec![
+            ((None, None), Ok(())),
+            ((Some(later), None), Ok(())),
+            ((None, Some(earlier)), Ok(())),
+            ((Some(earlier), Some(earlier)), Ok(())),
+            ((Some(earlier), Some(later)), Ok(())),
+            ((Some(later), Some(earlier)), Err(())),
+        ];
+
+        for ((from, to), expected) in tests {
+            assert_eq!(expected, super::time_range(from, to).map_err(|_| ()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consumer_args_table() {
+        let args =
+            |json: serde_json::Value| crate::ConsumerArgs(serde_json::from_value(json).unwrap());
+
+        let table = super::consumer_args_table(Some(&args(serde_json::json!({
+            "x-priority": 5,
+            "x-stream-filter": ["eu", "us"],
+            "x-stream-match-unfiltered": true,
+            "x-ratio": 0.5,
+            "x-nothing": null,
+            "x-nested": {"level": {"value": "deep"}},
+        }))))
+        .unwrap();
+        let table = table.inner();
+        assert_eq!(table.get("x-priority"), Some(&AMQPValue::LongLongInt(5)));
+        assert_eq!(
+            table.get("x-stream-filter"),
+            Some(&AMQPValue::FieldArray(
+                vec![
+                    AMQPValue::LongString("eu".into()),
+                    AMQPValue::LongString("us".into())
+                ]
+                .into()
+            ))
+        );
+        assert_eq!(
+            table.get("x-stream-match-unfiltered"),
+            Some(&AMQPValue::Boolean(true))
+        );
+        assert_eq!(table.get("x-ratio"), Some(&AMQPValue::Double(0.5)));
+        assert_eq!(table.get("x-nothing"), Some(&AMQPValue::Void));
+        assert!(matches!(
+            table.get("x-nested"),
+            Some(AMQPValue::FieldTable(_))
+        ));
+
+        let invalid = vec![
+            serde_json::json!({"x-too-deep": {"a": {"b": {"c": 1}}}}),
+            serde_json::json!({"x-too-deep": [[[1]]]}),
+            serde_json::json!({"x-too-big": u64::MAX}),
+            serde_json::json!({"": 1}),
+        ];
+        for json in invalid {
+            assert!(super::consumer_args_table(Some(&args(json))).is_err());
+        }
+
+        assert!(super::consumer_args_table(None).unwrap().inner().is_empty());
+
+        //explicit offsets win over the consumer arguments
+        let table = super::consumer_args_table(Some(&args(serde_json::json!({
+            "x-stream-offset": "last",
+        }))))
+        .unwrap();
+        let merged = super::stream_consume_args(AMQPValue::LongString("first".into()), table);
+        assert_eq!(
+            merged.inner().get("x-stream-offset"),
+            Some(&AMQPValue::LongString("first".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redact() {
+        let message = || super::Message {
+            offset: Some(1),
+            transactions: vec![
+                super::TransactionHeader {
+                    name: "x-stream-transaction-id".to_string(),
+                    value: "transaction_1".to_string(),
+                },
+                super::TransactionHeader {
+                    name: "x-saga-id".to_string(),
+                    value: "saga_1".to_string(),
+                },
+            ],
+            app_id: None,
+            published_to: None,
+            replayed: None,
+            timestamp: None,
+            size_bytes: 6,
+            encoding: super::PayloadEncoding::Utf8,
+            data: "secret".to_string(),
+        };
+
+        let tests = vec![
+            (vec![], ["transaction_1", "saga_1"], "secret"),
+            (
+                vec!["x-stream-transaction-id"],
+                ["[REDACTED]", "saga_1"],
+                "secret",
+            ),
+            (
+                vec!["x-saga-id", "x-stream-transaction-id"],
+                ["[REDACTED]", "[REDACTED]"],
+                "secret",
+            ),
+            (vec!["body"], ["transaction_1", "saga_1"], "[REDACTED]"),
+            (
+                vec!["body", "x-stream-transaction-id"],
+                ["[REDACTED]", "saga_1"],
+                "[REDACTED]",
+            ),
+            (
+                vec!["x-other-header"],
+                ["transaction_1", "saga_1"],
+                "secret",
+            ),
+        ];
+
+        for (fields, transactions, data) in tests {
+            let mut message = message();
+            message.redact(&fields);
+            let values: Vec<&str> = message
+                .transactions
+                .iter()
+                .map(|transaction| transaction.value.as_str())
+                .collect();
+            assert_eq!(values, transactions);
+            assert_eq!(message.data, data);
+            assert_eq!(message.offset, Some(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_round_trip() {
+        let timestamp = Utc.timestamp_millis_opt(1697443200123).unwrap();
+        let messages = vec![
+            super::Message {
+                offset: Some(42),
+                transactions: vec![super::TransactionHeader {
+                    name: "x-stream-transaction-id".to_string(),
+                    value: "transaction_42".to_string(),
+                }],
+                app_id: Some("billing".to_string()),
+                published_to: None,
+                replayed: None,
+                timestamp: Some(timestamp),
+                size_bytes: 4,
+                encoding: super::PayloadEncoding::Utf8,
+                data: "test".to_string(),
+            },
+            super::Message {
+                offset: None,
+                transactions: vec![],
+                app_id: None,
+                published_to: None,
+                replayed: Some(super::Provenance {
+                    from_offset: 42,
+                    original_timestamp: Some(timestamp),
+                    replayed_at: None,
+                }),
+                timestamp: None,
+                size_bytes: 4,
+                encoding: super::PayloadEncoding::Utf8,
+                data: "test".to_string(),
+            },
+        ];
+        for message in messages {
+            let json = serde_json::to_string(&message).unwrap();
+            assert_eq!(
+                serde_json::from_str::<super::Message>(&json).unwrap(),
+                message
+            );
+        }
+
+        //the offset is left out of replayed messages, which don't have one
+        assert_eq!(
+            serde_json::to_string(&super::Message {
+                offset: None,
+                transactions: vec![],
+                app_id: None,
+                published_to: None,
+                replayed: None,
+                timestamp: None,
+                size_bytes: 4,
+                encoding: super::PayloadEncoding::Utf8,
+                data: "test".to_string(),
+            })
+            .unwrap(),
+            r#"{"transactions":[],"timestamp":null,"size_bytes":4,"encoding":"utf8","data":"test"}"#
+        );
+
+        let offsets = super::StreamOffsets {
+            queue: "replay".to_string(),
+            messages: 100,
+            first_offset: Some(0),
+            last_offset: Some(99),
+            first_timestamp: Some(timestamp),
+            last_timestamp: None,
+        };
+        let json = serde_json::to_string(&offsets).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::StreamOffsets>(&json).unwrap(),
+            offsets
+        );
+
+        let consumer_offset = super::ConsumerOffset {
+            consumer_tag: "billing".to_string(),
+            current_offset: 40,
+            lag: 59,
+        };
+        let json = serde_json::to_string(&consumer_offset).unwrap();
+        assert_eq!(
+            serde_json::from_str::<super::ConsumerOffset>(&json).unwrap(),
+            consumer_offset
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_offset() {
+        let delivery = |headers: Option<FieldTable>| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: match headers {
+                Some(headers) => lapin::BasicProperties::default().with_headers(headers),
+                None => lapin::BasicProperties::default(),
+            },
+            data: b"test".to_vec(),
+            acker: Default::default(),
+        };
+
+        let mut stream_headers = FieldTable::default();
+        stream_headers.insert(
+            ShortString::from("x-stream-offset"),
+            AMQPValue::LongLongInt(42),
+        );
+        let mut classic_headers = FieldTable::default();
+        classic_headers.insert(
+            ShortString::from("x-stream-transaction-id"),
+            AMQPValue::LongString("transaction_1".into()),
+        );
+        let mut wrong_type_headers = FieldTable::default();
+        wrong_type_headers.insert(
+            ShortString::from("x-stream-offset"),
+            AMQPValue::LongString("42".into()),
+        );
+
+        assert_eq!(
+            super::stream_offset(&delivery(Some(stream_headers))).unwrap(),
+            42
+        );
+        for headers in [None, Some(classic_headers), Some(wrong_type_headers)] {
+            let err = super::stream_offset(&delivery(headers)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "x-stream-offset header not found on message, only stream queues can be read"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_consumer_offsets() {
+        let details = serde_json::json!({
+            "consumer_details": [
+                {"consumer_tag": "billing", "arguments": {"x-stream-offset": 40}},
+                {"consumer_tag": "audit", "arguments": {"x-stream-offset": 99}},
+                {"consumer_tag": "tail", "arguments": {"x-stream-offset": "next"}},
+                {"consumer_tag": "plain", "arguments": {}},
+                {"consumer_tag": "ahead", "arguments": {"x-stream-offset": 120}}
+            ]
+        });
+
+        let test_cases = vec![
+            (
+                details.clone(),
+                Some(99),
+                vec![("billing", 40, 59), ("audit", 99, 0), ("ahead", 120, 0)],
+            ),
+            (
+                details,
+                None,
+                vec![("billing", 40, 0), ("audit", 99, 0), ("ahead", 120, 0)],
+            ),
+            (
+                serde_json::json!({"consumer_details": []}),
+                Some(99),
+                vec![],
+            ),
+            (serde_json::json!({"messages": 100}), Some(99), vec![]),
+        ];
+
+        for (details, max_offset, expected) in test_cases {
+            let expected: Vec<super::ConsumerOffset> = expected
+                .into_iter()
+                .map(|(tag, current_offset, lag)| super::ConsumerOffset {
+                    consumer_tag: tag.to_string(),
+                    current_offset,
+                    lag,
+                })
+                .collect();
+            assert_eq!(
+                super::parse_consumer_offsets(&details, max_offset),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_correlation_id_filter() {
+        let delivery = |correlation_id: Option<&str>| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: match correlation_id {
+                Some(correlation_id) => {
+                    lapin::BasicProperties::default().with_correlation_id(correlation_id.into())
+                }
+                None => lapin::BasicProperties::default(),
+            },
+            data: b"test".to_vec(),
+            acker: Default::default(),
+        };
+        let filter = |value: &str, prefix: bool| super::MessageFilter {
+            correlation_id: Some(crate::CorrelationIdFilter {
+                value: value.to_string(),
+                prefix,
+            }),
+            ..Default::default()
+        };
+
+        let test_cases = vec![
+            (filter("order-1", false), Some("order-1"), true),
+            (filter("order-1", false), Some("order-12"), false),
+            (filter("order-1", false), Some("Order-1"), false),
+            (filter("order-1", true), Some("order-12"), true),
+            (filter("order-1", true), Some("order-1"), true),
+            (filter("order-1", true), Some("order-2"), false),
+            (filter("", true), Some("order-2"), true),
+            (filter("", true), None, false),
+            (filter("order-1", false), None, false),
+            (super::MessageFilter::default(), None, true),
+        ];
+        for (filter, correlation_id, expected) in test_cases {
+            assert_eq!(
+                filter.matches(&delivery(correlation_id)),
+                expected,
+                "{:?} {:?}",
+                filter.correlation_id,
+                correlation_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_value_matcher() {
+        use crate::{AMQPHeader, MatchType};
+
+        let matcher = |value: &str, match_type| {
+            super::ValueMatcher::new(&AMQPHeader {
+                name: "x-stream-transaction-id".to_string(),
+                value: value.to_string(),
+                match_type,
+            })
+        };
+        let tests = vec![
+            (
+                "batch-2024-06-01/0001",
+                MatchType::Exact,
+                "batch-2024-06-01/0001",
+                true,
+            ),
+            (
+                "batch-2024-06-01/0001",
+                MatchType::Exact,
+                "batch-2024-06-01/00011",
+                false,
+            ),
+            (
+                "batch-2024-06-01/",
+                MatchType::Exact,
+                "batch-2024-06-01/0001",
+                false,
+            ),
+            (
+                "batch-2024-06-01/",
+                MatchType::Prefix,
+                "batch-2024-06-01/0001",
+                true,
+            ),
+            (
+                "batch-2024-06-01/",
+                MatchType::Prefix,
+                "batch-2024-06-02/0001",
+                false,
+            ),
+            ("", MatchType::Prefix, "batch-2024-06-01/0001", true),
+            (
+                r"^batch-2024-06-0[1-3]/",
+                MatchType::Regex,
+                "batch-2024-06-02/0001",
+                true,
+            ),
+            (
+                r"^batch-2024-06-0[1-3]/",
+                MatchType::Regex,
+                "batch-2024-06-04/0001",
+                false,
+            ),
+            (r"/0001$", MatchType::Regex, "batch-2024-06-01/0001", true),
+            (r"0001", MatchType::Regex, "batch-2024-06-01/00012", true),
+            ("batch.2024", MatchType::Prefix, "batch-2024", false),
+            ("batch.2024", MatchType::Regex, "batch-2024", true),
+        ];
+        for (value, match_type, header, expected) in tests {
+            assert_eq!(
+                matcher(value, match_type).unwrap().matches(header),
+                expected,
+                "{} {:?} {}",
+                value,
+                match_type,
+                header
+            );
+        }
+
+        let err = matcher("batch-(2024", MatchType::Regex).err().unwrap();
+        assert_eq!(err.status_code(), axum::http::StatusCode::BAD_REQUEST);
+        //only regexes are parsed
+        assert!(matcher("batch-(2024", MatchType::Prefix).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_app_id_filter() {
+        let delivery = |app_id: Option<&str>, correlation_id: Option<&str>| {
+            let properties = lapin::BasicProperties::default();
+            let properties = match app_id {
+                Some(app_id) => properties.with_app_id(app_id.into()),
+                None => properties,
+            };
+            let properties = match correlation_id {
+                Some(correlation_id) => properties.with_correlation_id(correlation_id.into()),
+                None => properties,
+            };
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties,
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+        let filter = |app_id: &str, correlation_id: Option<&str>| super::MessageFilter {
+            app_id: Some(app_id.to_string()),
+            correlation_id: correlation_id.map(|value| crate::CorrelationIdFilter {
+                value: value.to_string(),
+                prefix: false,
+            }),
+            ..Default::default()
+        };
+
+        let test_cases = vec![
+            (filter("billing", None), Some("billing"), None, true),
+            (filter("billing", None), Some("shipping"), None, false),
+            (filter("billing", None), Some("Billing"), None, false),
+            (filter("billing", None), Some("billing-v2"), None, false),
+            (filter("billing", None), None, None, false),
+            (filter("", None), None, None, false),
+            (
+                filter("billing", Some("order-1")),
+                Some("billing"),
+                Some("order-1"),
+                true,
+            ),
+            (
+                filter("billing", Some("order-1")),
+                Some("billing"),
+                Some("order-2"),
+                false,
+            ),
+            (
+                filter("billing", Some("order-1")),
+                Some("shipping"),
+                Some("order-1"),
+                false,
+            ),
+            (super::MessageFilter::default(), Some("billing"), None, true),
+        ];
+        for (filter, app_id, correlation_id, expected) in test_cases {
+            assert_eq!(
+                filter.matches(&delivery(app_id, correlation_id)),
+                expected,
+                "{:?} {:?} {:?}",
+                filter.app_id,
+                app_id,
+                correlation_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_absent_filter() {
+        let delivery = |headers: Vec<(&str, lapin::types::AMQPValue)>| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: match headers.is_empty() {
+                true => lapin::BasicProperties::default(),
+                false => {
+                    let mut table = lapin::types::FieldTable::default();
+                    for (name, value) in headers {
+                        table.insert(name.into(), value);
+                    }
+                    lapin::BasicProperties::default().with_headers(table)
+                }
+            },
+            data: b"test".to_vec(),
+            acker: Default::default(),
+        };
+        let filter = super::MessageFilter {
+            header_absent: super::header_absent_path(Some("x-schema-version")).unwrap(),
+            ..Default::default()
+        };
+        let version = lapin::types::AMQPValue::LongString("2".into());
+
+        let test_cases = vec![
+            (vec![], true),
+            (vec![("x-other", version.clone())], true),
+            (
+                vec![("x-schema-version", lapin::types::AMQPValue::Void)],
+                true,
+            ),
+            (vec![("x-schema-version", version.clone())], false),
+            (
+                vec![
+                    ("x-other", version.clone()),
+                    ("x-schema-version", lapin::types::AMQPValue::LongUInt(0)),
+                ],
+                false,
+            ),
+        ];
+        for (headers, expected) in test_cases {
+            let description = format!("{:?}", headers);
+            assert_eq!(
+                filter.matches(&delivery(headers)),
+                expected,
+                "{}",
+                description
+            );
+        }
+
+        assert!(super::header_absent_path(None).unwrap().is_none());
+        assert!(super::header_absent_path(Some("")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_skip_replayed_filter() {
+        let delivery = |headers: Vec<(&str, AMQPValue)>| {
+            let mut table = FieldTable::default();
+            for (name, value) in headers {
+                table.insert(name.into(), value);
+            }
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties: lapin::BasicProperties::default().with_headers(table),
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+        let skip = super::MessageFilter {
+            skip_replayed: true,
+            ..Default::default()
+        };
+        let keep = super::MessageFilter::default();
+
+        let test_cases = vec![
+            (vec![], true),
+            (vec![("x-stream-offset", AMQPValue::LongLongInt(7))], true),
+            (
+                vec![(
+                    super::REPLAYED_FROM_OFFSET_HEADER,
+                    AMQPValue::LongLongInt(7),
+                )],
+                false,
+            ),
+            //an offset of any integer width marks a copy
+            (
+                vec![(super::REPLAYED_FROM_OFFSET_HEADER, AMQPValue::LongUInt(7))],
+                false,
+            ),
+            //the time of the replay alone doesn't make a copy
+            (
+                vec![(super::REPLAYED_AT_HEADER, AMQPValue::LongLongInt(0))],
+                true,
+            ),
+        ];
+        for (headers, expected) in test_cases {
+            let description = format!("{:?}", headers);
+            let delivery = delivery(headers);
+            assert_eq!(skip.matches(&delivery), expected, "{}", description);
+            assert!(keep.matches(&delivery), "{}", description);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gap_tracker() {
+        let range = |start, end| super::OffsetRange { start, end };
+        let test_cases = vec![
+            (vec![], vec![], vec![]),
+            (vec![(0, true), (1, true), (2, true)], vec![], vec![]),
+            (
+                vec![(0, true), (1, false), (2, false), (3, true), (4, false)],
+                vec![range(1, 2), range(4, 4)],
+                vec![],
+            ),
+            (vec![(3, true), (4, true)], vec![], vec![range(0, 2)]),
+            (
+                vec![
+                    (5, true),
+                    (6, false),
+                    (7, false),
+                    (8, true),
+                    (10, false),
+                    (11, true),
+                    (14, true),
+                ],
+                vec![range(6, 7), range(10, 10)],
+                vec![range(0, 4), range(9, 9), range(12, 13)],
+            ),
+            (
+                vec![(2, false), (4, false)],
+                vec![range(2, 2), range(4, 4)],
+                vec![range(0, 1), range(3, 3)],
+            ),
+        ];
+        for (offsets, filtered, missing) in test_cases {
+            let mut tracker = super::GapTracker::default();
+            for (offset, matched) in &offsets {
+                tracker.add(*offset, *matched);
+            }
+            assert_eq!(tracker.gaps.filtered, filtered, "{:?}", offsets);
+            assert_eq!(tracker.gaps.missing, missing, "{:?}", offsets);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn events(&self, message: &str) -> usize {
+            let logs = self.0.lock().unwrap();
+            String::from_utf8_lossy(&logs)
+                .lines()
+                .filter(|line| line.contains(message))
+                .count()
+        }
+    }
+
+    fn capture_progress(
+        log: super::ProgressLog,
+        run: impl FnOnce(&mut super::Progress),
+    ) -> CapturedLogs {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let mut progress = super::Progress::new("replay", "orders", log);
+            run(&mut progress);
+        });
+        logs
+    }
+
+    #[tokio::test]
+    async fn test_progress_log() {
+        let hour = std::time::Duration::from_secs(3600);
+
+        let every_ten = super::ProgressLog {
+            every_messages: 10,
+            interval: hour,
+        };
+        let logs = capture_progress(every_ten, |progress| {
+            for offset in 0..25 {
+                progress.scanned(offset, offset % 2 == 0);
+            }
+        });
+        assert_eq!(logs.events(" progress "), 2);
+        assert_eq!(logs.events(" finished "), 1);
+        assert_eq!(logs.events("scanned=25 matched=13 published=0"), 1);
+
+        //a slow loop still logs once the interval passed
+        let throttled = super::ProgressLog {
+            every_messages: 0,
+            interval: std::time::Duration::from_millis(20),
+        };
+        let logs = capture_progress(throttled, |progress| {
+            for offset in 0..3 {
+                std::thread::sleep(std::time::Duration::from_millis(25));
+                progress.published(Some(offset));
+            }
+        });
+        assert_eq!(logs.events(" progress "), 3);
+        assert_eq!(logs.events(" finished "), 1);
+        assert_eq!(logs.events("current_offset=Some(2)"), 2);
+
+        let quiet = super::ProgressLog {
+            every_messages: 0,
+            interval: hour,
+        };
+        let logs = capture_progress(quiet, |progress| progress.scanned(0, true));
+        assert_eq!(logs.events(" progress "), 0);
+        assert_eq!(logs.events(" finished "), 1);
+    }
+
+    #[tokio::test]
+    async fn test_track_job_progress() {
+        let job = std::sync::Arc::new(super::JobProgress::default());
+        super::track_job_progress(job.clone(), async {
+            let mut scan = super::Progress::new("replay", "orders", super::ProgressLog::DEFAULT);
+            for offset in 0..5 {
+                scan.scanned(offset, offset % 2 == 0);
+            }
+            let mut publish =
+                super::Progress::new("publish", "orders", super::ProgressLog::DEFAULT);
+            publish.published(Some(0));
+            publish.published(None);
+        })
+        .await;
+        assert_eq!(
+            job.counts(),
+            super::JobCounts {
+                scanned: 5,
+                matched: 3,
+                published: 2,
+                last_offset: Some(0),
+            }
+        );
+
+        //loops outside of a job leave its counts alone
+        let mut progress = super::Progress::new("replay", "orders", super::ProgressLog::DEFAULT);
+        progress.scanned(7, true);
+        assert_eq!(job.counts().scanned, 5);
+    }
+
+    #[tokio::test]
+    async fn test_progress_records_span() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "scan",
+                scanned = tracing::field::Empty,
+                matched = tracing::field::Empty
+            );
+            let _entered = span.enter();
+            let mut progress =
+                super::Progress::new("replay", "orders", super::ProgressLog::DEFAULT);
+            for offset in 0..4 {
+                progress.scanned(offset, offset == 0);
+            }
+            drop(progress);
+            tracing::info!("done");
+        });
+        assert_eq!(logs.events("scan{scanned=4 matched=1}: "), 2);
+    }
+
+    fn read_fetched(fetched: super::FetchedMessages) -> (Vec<u8>, bool) {
+        match fetched {
+            super::FetchedMessages::InMemory(bytes) => (bytes, false),
+            super::FetchedMessages::Spilled {
+                mut file,
+                path: _path,
+            } => {
+                let mut bytes = Vec::new();
+                std::io::Read::read_to_end(&mut file, &mut bytes).unwrap();
+                (bytes, true)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let files_in_dir = || std::fs::read_dir(dir.path()).unwrap().count();
+        let messages: Vec<super::Message> = (0..50)
+            .map(|i| super::Message {
+                offset: Some(i),
+                transactions: vec![super::TransactionHeader {
+                    name: "x-stream-transaction-id".to_string(),
+                    value: format!("transaction_{}", i),
+                }],
+                app_id: None,
+                published_to: None,
+                replayed: None,
+                timestamp: Some(Utc.timestamp_millis_opt(1_700_000_000_000).unwrap()),
+                size_bytes: 4 * i as usize,
+                encoding: super::PayloadEncoding::Utf8,
+                data: "test".repeat(i as usize),
+            })
+            .collect();
+
+        let tests = vec![
+            (0, 0, false),
+            (0, 1, true),
+            (0, 50, true),
+            (1_000, 5, false),
+            (1_000, 50, true),
+            (u64::MAX, 50, false),
+        ];
+        for (threshold_bytes, count, spilled) in tests {
+            let spill_options = crate::SpillOptions {
+                threshold_bytes,
+                dir: dir.path().to_path_buf(),
+            };
+            let mut sink = super::MessageSink::new(&spill_options);
+            for message in &messages[..count] {
+                sink.add(message).unwrap();
+            }
+            let (bytes, was_spilled) = read_fetched(sink.finish().unwrap());
+            assert_eq!(was_spilled, spilled, "{} {}", threshold_bytes, count);
+            assert_eq!(
+                bytes,
+                serde_json::to_vec(&messages[..count]).unwrap(),
+                "{} {}",
+                threshold_bytes,
+                count
+            );
+            assert_eq!(files_in_dir(), 0, "{} {}", threshold_bytes, count);
+        }
+
+        //a fetch that fails after spilling leaves no file behind
+        let spill_options = crate::SpillOptions {
+            threshold_bytes: 0,
+            dir: dir.path().to_path_buf(),
+        };
+        let mut sink = super::MessageSink::new(&spill_options);
+        sink.add(&messages[0]).unwrap();
+        assert_eq!(files_in_dir(), 1);
+        drop(sink);
+        assert_eq!(files_in_dir(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_tracker() {
+        let mut tracker = super::DuplicateTracker::default();
+        let values = vec![
+            Some("transaction_1"),
+            Some("transaction_2"),
+            None,
+            Some("transaction_2"),
+            Some("transaction_3"),
+            Some("transaction_1"),
+            None,
+            Some("transaction_2"),
+        ];
+        for (offset, value) in values.into_iter().enumerate() {
+            tracker.add(value.map(str::to_string), offset as u64);
+        }
+
+        let group = |value: &str, offsets: Vec<u64>| super::DuplicateGroup {
+            value: value.to_string(),
+            count: offsets.len() as u64,
+            offsets,
+        };
+        assert_eq!(
+            tracker.into_report("x-stream-transaction-id"),
+            super::DuplicateReport {
+                header: "x-stream-transaction-id".to_string(),
+                groups: vec![
+                    group("transaction_1", vec![0, 5]),
+                    group("transaction_2", vec![1, 3, 7]),
+                ],
+                without_header: 2,
+                truncated: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_tracker_bounds() {
+        let mut tracker = super::DuplicateTracker::default();
+        for offset in 0..super::MAX_DUPLICATE_GROUPS as u64 {
+            tracker.add(Some(format!("transaction_{}", offset)), offset);
+        }
+        let next = super::MAX_DUPLICATE_GROUPS as u64;
+        tracker.add(Some("untracked".to_string()), next);
+        tracker.add(Some("untracked".to_string()), next + 1);
+        for offset in 0..super::MAX_DUPLICATE_OFFSETS as u64 + 10 {
+            tracker.add(Some("transaction_0".to_string()), next + 2 + offset);
+        }
+
+        let report = tracker.into_report("x-stream-transaction-id");
+        assert!(report.truncated);
+        assert_eq!(report.groups.len(), 1);
+        assert_eq!(report.groups[0].value, "transaction_0");
+        assert_eq!(
+            report.groups[0].count,
+            super::MAX_DUPLICATE_OFFSETS as u64 + 11
+        );
+        assert_eq!(report.groups[0].offsets.len(), super::MAX_DUPLICATE_OFFSETS);
+    }
+
+    #[tokio::test]
+    async fn test_replay_preview() {
+        let delivery = |timestamp: Option<u64>, data: &[u8]| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: match timestamp {
+                Some(timestamp) => lapin::BasicProperties::default().with_timestamp(timestamp),
+                None => lapin::BasicProperties::default(),
+            },
+            data: data.to_vec(),
+            acker: Default::default(),
+        };
+
+        let mut preview = super::ReplayPreview::default();
+        preview.add(&delivery(Some(1697443200000), b"first"), 3);
+        preview.add(&delivery(None, b""), 7);
+        preview.add(&delivery(Some(1697443260000), b"last"), 12);
+
+        assert_eq!(
+            preview,
+            super::ReplayPreview {
+                matched_count: 3,
+                first_offset: Some(3),
+                last_offset: Some(12),
+                first_timestamp: Utc.timestamp_millis_opt(1697443200000).single(),
+                last_timestamp: Utc.timestamp_millis_opt(1697443260000).single(),
+                estimated_bytes: 9,
+                stream_last_offset: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_order_messages() {
+        let delivery = |timestamp: Option<u64>, data: &str| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: match timestamp {
+                Some(timestamp) => lapin::BasicProperties::default().with_timestamp(timestamp),
+                None => lapin::BasicProperties::default(),
+            },
+            data: data.as_bytes().to_vec(),
+            acker: Default::default(),
+        };
+        let messages = || {
+            vec![
+                delivery(Some(30), "a"),
+                delivery(None, "b"),
+                delivery(Some(10), "c"),
+                delivery(Some(30), "d"),
+                delivery(Some(20), "e"),
+                delivery(None, "f"),
+            ]
+        };
+        let data = |messages: Vec<lapin::message::Delivery>| {
+            messages
+                .into_iter()
+                .map(|message| String::from_utf8(message.data).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let tests = vec![
+            (None, false, vec!["a", "b", "c", "d", "e", "f"]),
+            (
+                Some(crate::ReplayOrder::Offset),
+                true,
+                vec!["a", "b", "c", "d", "e", "f"],
+            ),
+            (
+                Some(crate::ReplayOrder::Timestamp),
+                false,
+                vec!["c", "e", "a", "d", "b", "f"],
+            ),
+            (
+                Some(crate::ReplayOrder::Timestamp),
+                true,
+                vec!["c", "e", "a", "d"],
+            ),
+        ];
+
+        for (order_by, exclude_missing_timestamps, expected) in tests {
+            assert_eq!(
+                data(super::order_messages(
+                    messages(),
+                    order_by,
+                    exclude_missing_timestamps
+                )),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matched_messages() {
+        let delivery = || lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: lapin::BasicProperties::default(),
+            data: vec![],
+            acker: Default::default(),
+        };
+
+        let mut messages = super::MatchedMessages::new(None, Some(4));
+        for _ in 0..4 {
+            messages.push(delivery()).unwrap();
+        }
+        let err = messages.push(delivery()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::ReplayError>(),
+            Some(crate::ReplayError::OrderWindowTooLarge(4))
+        ));
+        assert_eq!(messages.into_messages().unwrap().len(), 4);
+
+        //past the cap the messages are only counted, the order window isn't reached anymore
+        let mut messages = super::MatchedMessages::new(Some(3), Some(4));
+        for _ in 0..10 {
+            messages.push(delivery()).unwrap();
+        }
+        assert!(messages.messages.is_empty());
+        let err = messages.into_messages().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<crate::ReplayError>(),
+            Some(crate::ReplayError::TooManyMessages(10, 3))
+        ));
+
+        let mut messages = super::MatchedMessages::new(Some(3), None);
+        for _ in 0..3 {
+            messages.push(delivery()).unwrap();
+        }
+        assert_eq!(messages.into_messages().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_route_to_queue() {
+        let delivery = |exchange: &str, routing_key: &str| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            redelivered: false,
+            properties: lapin::BasicProperties::default(),
+            data: b"test".to_vec(),
+            acker: Default::default(),
+        };
+
+        let mut messages = vec![
+            delivery("orders", "order.created"),
+            delivery("", "replay"),
+            delivery("amq.topic", "order.#"),
+        ];
+        super::route_to_queue(&mut messages, "replay");
+        for message in messages {
+            assert_eq!(message.exchange.as_str(), "");
+            assert_eq!(message.routing_key.as_str(), "replay");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_choose_delay_strategy() {
+        use super::DelayStrategy::{Header, Hold};
+
+        let test_cases = vec![
+            (vec![true], Header(500)),
+            (vec![true, true], Header(500)),
+            (vec![true, false], Hold(500)),
+            (vec![false], Hold(500)),
+            (vec![], Hold(500)),
+        ];
+        for (delayed_exchanges, expected) in test_cases {
+            assert_eq!(
+                super::choose_delay_strategy(&delayed_exchanges, 500),
+                expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_delay_header() {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            ShortString::from("x-stream-transaction-id"),
+            AMQPValue::LongString("transaction_1".into()),
+        );
+        let test_cases = vec![
+            (lapin::BasicProperties::default(), 1),
+            (lapin::BasicProperties::default().with_headers(headers), 2),
+        ];
+        for (properties, header_count) in test_cases {
+            let properties = super::with_delay_header(properties, 500);
+            let headers = properties.headers().as_ref().unwrap();
+            assert_eq!(headers.inner().len(), header_count);
+            assert_eq!(
+                headers.inner().get("x-delay"),
+                Some(&AMQPValue::LongLongInt(500))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pacer() {
+        let hour = std::time::Duration::from_secs(60 * 60);
+        let test_cases = vec![
+            (
+                1.0,
+                hour,
+                vec![Some(1_000), Some(31_000), Some(32_000)],
+                vec![0, 30_000, 1_000],
+            ),
+            (
+                2.0,
+                hour,
+                vec![Some(1_000), Some(31_000), Some(32_000)],
+                vec![0, 15_000, 500],
+            ),
+            (10.0, hour, vec![Some(0), Some(30_000)], vec![0, 3_000]),
+            (0.5, hour, vec![Some(0), Some(1_000)], vec![0, 2_000]),
+            //messages without a timestamp go out right away and don't reset the gap
+            (
+                1.0,
+                hour,
+                vec![None, Some(1_000), None, Some(3_000)],
+                vec![0, 0, 0, 2_000],
+            ),
+            (
+                1.0,
+                hour,
+                vec![Some(5_000), Some(2_000), Some(4_000)],
+                vec![0, 0, 2_000],
+            ),
+            (1.0, hour, vec![Some(1_000), Some(1_000)], vec![0, 0]),
+            //the pauses are cut off at the max duration
+            (
+                1.0,
+                std::time::Duration::from_secs(45),
+                vec![Some(0), Some(30_000), Some(60_000), Some(90_000)],
+                vec![0, 30_000, 15_000, 0],
+            ),
+            (
+                1.0,
+                std::time::Duration::ZERO,
+                vec![Some(0), Some(30_000)],
+                vec![0, 0],
+            ),
+            (
+                f64::MIN_POSITIVE,
+                hour,
+                vec![Some(0), Some(1)],
+                vec![0, 3_600_000],
+            ),
+        ];
+        for (speed, max_duration, timestamps, expected) in test_cases {
+            let mut pacer = super::Pacer::new(crate::Pacing::original(speed), max_duration);
+            let pauses: Vec<u128> = timestamps
+                .iter()
+                .map(|timestamp| pacer.pause(*timestamp).as_millis())
+                .collect();
+            assert_eq!(pauses, expected, "{} {:?}", speed, timestamps);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let ms = std::time::Duration::from_millis;
+        let test_cases = vec![
+            (4, vec![0, 0, 0], vec![0, 250, 500]),
+            //the token of a late publish isn't saved up
+            (4, vec![0, 100, 2_000, 2_000], vec![0, 150, 0, 250]),
+            (1, vec![0, 999, 1_000], vec![0, 1, 1_000]),
+            (1000, vec![0, 0, 5], vec![0, 1, 0]),
+            //zero would never publish and is refused by the validation, it is treated as one
+            (0, vec![0, 0], vec![0, 1_000]),
+        ];
+        for (rate_limit, calls_ms, expected) in test_cases {
+            let mut rate_limiter = super::RateLimiter::new(rate_limit);
+            let start = tokio::time::Instant::now();
+            let pauses: Vec<u128> = calls_ms
+                .iter()
+                .map(|call_ms| rate_limiter.pause(start + ms(*call_ms)).as_millis())
+                .collect();
+            assert_eq!(pauses, expected, "{} {:?}", rate_limit, calls_ms);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_properties_delivery_mode() {
+        let message_options = |publish_persistent| crate::MessageOptions {
+            transaction_headers: vec!["x-stream-transaction-id".to_string()],
+            enable_timestamp: true,
+            publish_mandatory: false,
+            publish_persistent,
+            ..Default::default()
+        };
+        let test_cases = vec![
+            (true, None, Some(2)),
+            (true, Some(super::DelayStrategy::Header(500)), Some(2)),
+            (false, None, None),
+        ];
+        for (publish_persistent, delay, delivery_mode) in test_cases {
+            let (properties, transaction, timestamp) = super::replay_properties(
+                &lapin::BasicProperties::default(),
+                &message_options(publish_persistent),
+                None,
+                delay,
+            );
+            assert_eq!(*properties.delivery_mode(), delivery_mode);
+            assert!(!transaction.is_empty() && timestamp.is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_properties_carry_over() {
+        let mut headers = FieldTable::default();
+        for (name, value) in [
+            ("x-stream-transaction-id", "transaction_1"),
+            ("tenant-id", "acme"),
+        ] {
+            headers.insert(ShortString::from(name), AMQPValue::LongString(value.into()));
+        }
+        headers.insert(
+            ShortString::from("x-stream-offset"),
+            AMQPValue::LongLongInt(42),
+        );
+        let consumed = lapin::BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_content_encoding("gzip".into())
+            .with_correlation_id("order-42".into())
+            .with_message_id("message-42".into())
+            .with_priority(7)
+            .with_delivery_mode(1)
+            .with_timestamp(1697443200123)
+            .with_headers(headers);
+        let message_options = |enable_timestamp, publish_persistent| crate::MessageOptions {
+            transaction_headers: vec!["x-stream-transaction-id".to_string()],
+            enable_timestamp,
+            publish_mandatory: false,
+            publish_persistent,
+            ..Default::default()
+        };
+
+        let (properties, transactions, timestamp) =
+            super::replay_properties(&consumed, &message_options(false, false), None, None);
+        assert_eq!(
+            properties
+                .content_type()
+                .as_ref()
+                .map(|value| value.as_str()),
+            Some("application/json")
+        );
+        assert_eq!(
+            properties
+                .content_encoding()
+                .as_ref()
+                .map(|value| value.as_str()),
+            Some("gzip")
+        );
+        assert_eq!(
+            properties
+                .correlation_id()
+                .as_ref()
+                .map(|value| value.as_str()),
+            Some("order-42")
+        );
+        assert_eq!(
+            properties.message_id().as_ref().map(|value| value.as_str()),
+            Some("message-42")
+        );
+        assert_eq!(*properties.priority(), Some(7));
+        assert_eq!(*properties.delivery_mode(), Some(1));
+        assert_eq!(*properties.timestamp(), Some(1697443200123));
+        assert_eq!(
+            timestamp,
+            Some(Utc.timestamp_millis_opt(1697443200123).unwrap())
+        );
+        let headers = properties.headers().as_ref().unwrap().inner();
+        assert_eq!(
+            headers.get("tenant-id"),
+            Some(&AMQPValue::LongString("acme".into()))
+        );
+        assert_eq!(
+            headers.get("x-stream-transaction-id"),
+            Some(&AMQPValue::LongString(
+                transactions[0].value.as_str().into()
+            ))
+        );
+        assert!(!headers.contains_key("x-stream-offset"));
+        let provenance = super::Provenance::from_properties(&properties).unwrap();
+        assert_eq!(provenance.from_offset, 42);
+        assert_eq!(
+            provenance.original_timestamp,
+            Some(Utc.timestamp_millis_opt(1697443200123).unwrap())
+        );
+        assert!(provenance.replayed_at.is_some());
+
+        //a new timestamp and delivery mode if the options say so
+        let (properties, _, timestamp) =
+            super::replay_properties(&consumed, &message_options(true, true), None, None);
+        assert_ne!(*properties.timestamp(), Some(1697443200123));
+        assert_eq!(
+            *properties.timestamp(),
+            timestamp.map(|timestamp| timestamp.timestamp_millis() as u64)
+        );
+        assert_eq!(*properties.delivery_mode(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_offset_range_len() {
+        let test_cases = vec![
+            (10, Some(19), Some(0), Some(99), 10),
+            (10, None, Some(0), Some(99), 90),
+            (90, Some(200), Some(0), Some(99), 10),
+            //truncated by the retention
+            (0, Some(19), Some(15), Some(99), 5),
+            (100, None, Some(0), Some(99), 0),
+            (20, Some(10), Some(0), Some(99), 0),
+            (0, None, None, None, 0),
+        ];
+        for (from_offset, to_offset, first_offset, last_offset, expected) in test_cases {
+            let offset_replay = crate::OffsetReplay::new("replay", from_offset, to_offset);
+            assert_eq!(
+                super::offset_range_len(&offset_replay, first_offset, last_offset),
+                expected,
+                "{:?} in {:?}..={:?}",
+                offset_replay,
+                first_offset,
+                last_offset
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_is_last_offset() {
+        let test_cases = vec![
+            (0, 1, true),
+            (0, 2, false),
+            (98, 100, false),
+            (99, 100, true),
+            (150, 100, true),
+            (0, 0, true),
+            (-1, 100, false),
+            (i64::MAX, u64::MAX, false),
+        ];
+        for (offset, message_count, expected) in test_cases {
+            assert_eq!(
+                super::is_last_offset(offset, message_count),
+                expected,
+                "offset {} of {} messages",
+                offset,
+                message_count
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_matcher() {
+        let delivery = |value: AMQPValue| {
+            let mut headers = FieldTable::default();
+            headers.insert(ShortString::from("order-id"), value);
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties: lapin::BasicProperties::default().with_headers(headers),
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+
+        let tests = vec![
+            ("12345", AMQPValue::LongString("12345".into()), true),
+            ("12345", AMQPValue::LongLongInt(12345), true),
+            ("-42", AMQPValue::LongLongInt(-42), true),
+            ("12345", AMQPValue::LongLongInt(12346), false),
+            ("012345", AMQPValue::LongLongInt(12345), false),
+            ("12345", AMQPValue::LongInt(12345), true),
+            ("-7", AMQPValue::LongInt(-7), true),
+            ("7", AMQPValue::LongUInt(7), true),
+            ("-7", AMQPValue::ShortInt(-7), true),
+            ("7", AMQPValue::ShortUInt(7), true),
+            ("-7", AMQPValue::ShortShortInt(-7), true),
+            ("200", AMQPValue::ShortShortUInt(200), true),
+            ("12345", AMQPValue::ShortString("12345".into()), true),
+            ("true", AMQPValue::Boolean(true), true),
+            ("false", AMQPValue::Boolean(false), true),
+            //mismatched types don't match instead of failing the replay
+            ("true", AMQPValue::Boolean(false), false),
+            ("1", AMQPValue::Boolean(true), false),
+            ("true", AMQPValue::LongInt(1), false),
+            ("abc", AMQPValue::LongInt(12345), false),
+            ("1.5", AMQPValue::Double(1.5), false),
+            (
+                "12345",
+                AMQPValue::FieldArray(vec![AMQPValue::LongInt(12345)].into()),
+                false,
+            ),
+        ];
+
+        for (target, value, expected) in tests {
+            let header_replay = crate::HeaderReplay::new("replay", "order-id", target);
+            let is_match = super::header_matcher(&header_replay).unwrap();
+            let description = format!("{} {:?}", target, value);
+            assert_eq!(is_match(&delivery(value), 0), expected, "{}", description);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_start() {
+        let tests = vec![
+            (None, AMQPValue::LongString("first".into())),
+            (
+                Some(Utc.with_ymd_and_hms(2023, 10, 6, 14, 0, 30).unwrap()),
+                AMQPValue::Timestamp(1696600830 - super::STREAM_SEEK_MARGIN_SECS as u64),
+            ),
+            //sub-second precision is dropped, the seek lands before `from`
+            (
+                Some(Utc.timestamp_millis_opt(1696600830999).unwrap()),
+                AMQPValue::Timestamp(1696600830 - super::STREAM_SEEK_MARGIN_SECS as u64),
+            ),
+            (
+                Some(Utc.timestamp_opt(30, 0).unwrap()),
+                AMQPValue::Timestamp(0),
+            ),
+        ];
+
+        for (seek_to, expected) in tests {
+            assert_eq!(super::stream_start(seek_to), expected, "{:?}", seek_to);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_filtered_matcher() {
+        let at = |minute: u32| Utc.with_ymd_and_hms(2023, 10, 6, 14, minute, 0).unwrap();
+        let delivery = |minute: Option<u32>, tenant: Option<&str>| {
+            let mut headers = FieldTable::default();
+            if let Some(tenant) = tenant {
+                headers.insert(
+                    ShortString::from("tenant-id"),
+                    AMQPValue::LongString(tenant.into()),
+                );
+            }
+            let properties = lapin::BasicProperties::default().with_headers(headers);
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties: match minute {
+                    Some(minute) => properties.with_timestamp(at(minute).timestamp_millis() as u64),
+                    None => properties,
+                },
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+        let filtered =
+            |from: Option<u32>, to: Option<u32>, tenant: Option<&str>| crate::FilteredReplay {
+                from: from.map(at),
+                to: to.map(at),
+                header: tenant.map(|tenant| crate::AMQPHeader {
+                    name: "tenant-id".to_string(),
+                    value: tenant.to_string(),
+                    match_type: crate::MatchType::Exact,
+                }),
+                ..crate::FilteredReplay::new("replay")
+            };
+
+        let tests = vec![
+            (
+                (Some(0), Some(30), Some("acme")),
+                (Some(10), Some("acme")),
+                true,
+            ),
+            (
+                (Some(0), Some(30), Some("acme")),
+                (Some(40), Some("acme")),
+                false,
+            ),
+            (
+                (Some(0), Some(30), Some("acme")),
+                (Some(10), Some("initech")),
+                false,
+            ),
+            ((Some(0), Some(30), Some("acme")), (Some(10), None), false),
+            (
+                (Some(0), Some(30), Some("acme")),
+                (None, Some("acme")),
+                false,
+            ),
+            ((Some(0), None, None), (Some(40), None), true),
+            ((None, Some(30), None), (Some(40), None), false),
+            ((None, None, Some("acme")), (None, Some("acme")), true),
+            ((None, None, None), (None, None), true),
+        ];
+
+        for ((from, to, tenant), (minute, message_tenant), expected) in tests {
+            let is_match = super::filtered_matcher(&filtered(from, to, tenant)).unwrap();
+            assert_eq!(
+                is_match(&delivery(minute, message_tenant), 0),
+                expected,
+                "{:?} {:?} {:?} {:?} {:?}",
+                from,
+                to,
+                tenant,
+                minute,
+                message_tenant
+            );
+        }
+
+        assert!(super::filtered_matcher(&filtered(Some(30), Some(0), None)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_header_from_fieldtable() {
+        let header = "x-stream-transaction-id";
+        let mut table = FieldTable::default();
+        table.insert(ShortString::from("b"), AMQPValue::LongLongInt(2));
+        table.insert(ShortString::from("a"), AMQPValue::LongString("1".into()));
+        table.insert(ShortString::from("c"), AMQPValue::Double(1.5));
+
+        let tests = vec![
+            (
+                AMQPValue::LongString("transaction_1".into()),
+                Some("transaction_1"),
+            ),
+            (
+                AMQPValue::ShortString("transaction_1".into()),
+                Some("transaction_1"),
+            ),
+            (AMQPValue::ShortShortInt(-8), Some("-8")),
+            (AMQPValue::ShortShortUInt(8), Some("8")),
+            (AMQPValue::ShortInt(-16), Some("-16")),
+            (AMQPValue::ShortUInt(16), Some("16")),
+            (AMQPValue::LongInt(-32), Some("-32")),
+            (AMQPValue::LongUInt(32), Some("32")),
+            (AMQPValue::LongLongInt(64), Some("64")),
+            (
+                AMQPValue::ByteArray(b"transaction_1".to_vec().into()),
+                Some("transaction_1"),
+            ),
+            (
+                AMQPValue::ByteArray(vec![b'i', b'd', 0xff].into()),
+                Some("id\u{fffd}"),
+            ),
+            (
+                AMQPValue::FieldArray(
+                    vec![
+                        AMQPValue::LongString("first".into()),
+                        AMQPValue::LongString("second".into()),
+                    ]
+                    .into(),
+                ),
+                Some("first"),
+            ),
+            (AMQPValue::FieldArray(vec![].into()), None),
+            (AMQPValue::FieldTable(table), Some("a=1,b=2")),
+            (AMQPValue::FieldTable(FieldTable::default()), None),
+            (AMQPValue::Boolean(true), Some("true")),
+            (AMQPValue::Boolean(false), Some("false")),
+            (AMQPValue::Double(1.5), None),
+            (AMQPValue::Void, None),
+        ];
+
+        for (value, expected) in tests {
+            let description = format!("{:?}", value);
+            let mut headers = FieldTable::default();
+            headers.insert(ShortString::from(header), value);
+            let transaction = super::TransactionHeader::from_fieldtable(headers, header)
+                .ok()
+                .flatten();
+            assert_eq!(
+                transaction.map(|transaction| transaction.value),
+                expected.map(str::to_string),
+                "{}",
+                description
+            );
+        }
+
+        //a missing header is told apart from one that can't be read
+        let from_fieldtable = |value: Option<AMQPValue>| {
+            let mut headers = FieldTable::default();
+            if let Some(value) = value {
+                headers.insert(ShortString::from(header), value);
+            }
+            super::TransactionHeader::from_fieldtable(headers, header)
+        };
+        assert!(from_fieldtable(None).unwrap().is_none());
+        assert!(from_fieldtable(Some(AMQPValue::Void)).unwrap().is_none());
+        assert!(from_fieldtable(Some(AMQPValue::Double(1.5))).is_err());
+        assert!(from_fieldtable(Some(AMQPValue::FieldArray(vec![].into()))).is_err());
+        assert_eq!(
+            from_fieldtable(Some(AMQPValue::LongInt(42))).unwrap(),
+            Some(super::TransactionHeader {
+                name: header.to_string(),
+                value: "42".to_string(),
+            })
+        );
+        let transaction = super::TransactionHeader::lookup(
+            &[(
+                ShortString::from(header),
+                AMQPValue::ShortString("t".into()),
+            )]
+            .into_iter()
+            .collect::<std::collections::BTreeMap<_, _>>()
+            .into(),
+            header,
+        )
+        .unwrap();
+        assert_eq!(transaction.name, header);
+        assert_eq!(transaction.value, "t");
+    }
+
+    #[tokio::test]
+    async fn test_transaction_id_filter() {
+        let delivery = |headers: Vec<(&str, AMQPValue)>| {
+            let mut table = FieldTable::default();
+            for (name, value) in headers {
+                table.insert(ShortString::from(name), value);
+            }
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties: lapin::BasicProperties::default().with_headers(table),
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+        let message_options = |transaction_headers: &[&str]| crate::MessageOptions {
+            transaction_headers: transaction_headers.iter().map(|s| s.to_string()).collect(),
+            enable_timestamp: false,
+            publish_mandatory: false,
+            publish_persistent: true,
+            ..Default::default()
+        };
+        let query = |transaction_id: Option<&str>| crate::MessageQuery {
+            transaction_id: transaction_id.map(str::to_string),
+            ..crate::MessageQuery::new("replay")
+        };
+        let header = "x-stream-transaction-id";
+
+        let tests = vec![
+            (None, vec![], true),
+            (
+                Some("transaction_1"),
+                vec![(header, AMQPValue::LongString("transaction_1".into()))],
+                true,
+            ),
+            (Some("42"), vec![(header, AMQPValue::LongLongInt(42))], true),
+            (
+                Some("transaction_1"),
+                vec![(header, AMQPValue::LongString("transaction_10".into()))],
+                false,
+            ),
+            (
+                Some("transaction_1"),
+                vec![("x-other-id", AMQPValue::LongString("transaction_1".into()))],
+                false,
+            ),
+            (Some("transaction_1"), vec![], false),
+            (
+                Some("saga_1"),
+                vec![
+                    (header, AMQPValue::LongString("transaction_1".into())),
+                    ("x-saga-id", AMQPValue::LongString("saga_1".into())),
+                ],
+                true,
+            ),
+        ];
+
+        for (transaction_id, headers, expected) in tests {
+            let description = format!("{:?} {:?}", transaction_id, headers);
+            let is_match = super::message_query_matcher(
+                &query(transaction_id),
+                &message_options(&[header, "x-saga-id"]),
+            )
+            .unwrap();
+            assert_eq!(is_match(&delivery(headers), 0), expected, "{}", description);
+        }
+
+        let err =
+            super::message_query_matcher(&query(Some("transaction_1")), &message_options(&[]))
+                .err()
+                .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<crate::ReplayError>(),
+            Some(crate::ReplayError::TransactionHeaderNotConfigured)
+        ));
+        assert!(super::message_query_matcher(&query(None), &message_options(&[])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_transaction_headers() {
+        let message_options = crate::MessageOptions {
+            transaction_headers: vec!["x-txn-id".to_string(), "x-saga-id".to_string()],
+            enable_timestamp: false,
+            publish_mandatory: false,
+            publish_persistent: true,
+            ..Default::default()
+        };
+        let delivery = |headers: Vec<(&str, &str)>| {
+            let mut table = FieldTable::default();
+            for (name, value) in headers {
+                table.insert(ShortString::from(name), AMQPValue::LongString(value.into()));
+            }
+            lapin::message::Delivery {
+                delivery_tag: 1,
+                exchange: "".into(),
+                routing_key: "replay".into(),
+                redelivered: false,
+                properties: lapin::BasicProperties::default().with_headers(table),
+                data: b"test".to_vec(),
+                acker: Default::default(),
+            }
+        };
+
+        //extracted in the configured order, headers the message doesn't carry are left out
+        let tests = vec![
+            (
+                vec![("x-saga-id", "saga_1"), ("x-txn-id", "txn_1")],
+                vec![("x-txn-id", "txn_1"), ("x-saga-id", "saga_1")],
+            ),
+            (
+                vec![("x-saga-id", "saga_1"), ("x-other-id", "other_1")],
+                vec![("x-saga-id", "saga_1")],
+            ),
+            (vec![], vec![]),
+        ];
+        for (headers, expected) in tests {
+            let message = super::to_message(delivery(headers), 0, &message_options, false).unwrap();
+            let transactions: Vec<(&str, &str)> = message
+                .transactions
+                .iter()
+                .map(|transaction| (transaction.name.as_str(), transaction.value.as_str()))
+                .collect();
+            assert_eq!(transactions, expected);
+            assert_eq!(
+                message.published_to,
+                Some(crate::ReplayTarget {
+                    exchange: "".to_string(),
+                    routing_key: "replay".to_string(),
+                })
+            );
+        }
+
+        //every configured header is stamped with its own id
+        let (properties, transactions, _) = super::replay_properties(
+            &lapin::BasicProperties::default(),
+            &message_options,
+            None,
+            None,
+        );
+        let names: Vec<&str> = transactions
+            .iter()
+            .map(|transaction| transaction.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["x-txn-id", "x-saga-id"]);
+        assert_ne!(transactions[0].value, transactions[1].value);
+        let headers = properties.headers().as_ref().unwrap().inner();
+        for transaction in &transactions {
+            assert_eq!(
+                headers.get(transaction.name.as_str()),
+                Some(&AMQPValue::LongString(transaction.value.as_str().into()))
+            );
+        }
+
+        //a requested value replaces the new ids of every header and every message
+        for _ in 0..2 {
+            let (properties, transactions, _) = super::replay_properties(
+                &lapin::BasicProperties::default(),
+                &message_options,
+                Some("order-42"),
+                None,
+            );
+            assert_eq!(transactions.len(), 2);
+            let headers = properties.headers().as_ref().unwrap().inner();
+            for (transaction, name) in transactions.iter().zip(["x-txn-id", "x-saga-id"]) {
+                assert_eq!(transaction.name, name);
+                assert_eq!(transaction.value, "order-42");
+                assert_eq!(
+                    headers.get(name),
+                    Some(&AMQPValue::LongString("order-42".into()))
+                );
+            }
+        }
+
+        let (properties, transactions, _) = super::replay_properties(
+            &lapin::BasicProperties::default(),
+            &crate::MessageOptions {
+                transaction_headers: vec![],
+                ..message_options
+            },
+            None,
+            None,
+        );
+        assert!(transactions.is_empty());
+        //only the time of the replay, the message had neither an offset nor a timestamp
+        let headers = properties.headers().as_ref().unwrap().inner();
+        assert_eq!(
+            headers.keys().map(|name| name.as_str()).collect::<Vec<_>>(),
+            vec![super::REPLAYED_AT_HEADER]
+        );
+        assert_eq!(super::Provenance::from_properties(&properties), None);
+    }
+
+    #[tokio::test]
+    async fn test_size_filter() {
+        let delivery = |size: usize| lapin::message::Delivery {
+            delivery_tag: 1,
+            exchange: "".into(),
+            routing_key: "replay".into(),
+            redelivered: false,
+            properties: lapin::BasicProperties::default(),
+            data: vec![b'x'; size],
+            acker: Default::default(),
+        };
+        let message_options = crate::MessageOptions {
+            transaction_headers: vec![],
+            enable_timestamp: false,
+            publish_mandatory: false,
+            publish_persistent: true,
+            ..Default::default()
+        };
+        let query = |min_bytes: Option<usize>, max_bytes: Option<usize>| crate::MessageQuery {
+            min_bytes,
+            max_bytes,
+            ..crate::MessageQuery::new("replay")
+        };
+        let sizes = [0, 10, 1024, 9 * 1024 * 1024];
+
+        let tests = vec![
+            ((None, None), vec![0, 10, 1024, 9 * 1024 * 1024]),
+            ((Some(1024), None), vec![1024, 9 * 1024 * 1024]),
+            ((None, Some(10)), vec![0, 10]),
+            ((Some(11), Some(1024)), vec![1024]),
+            ((Some(1025), Some(1024 * 1024)), vec![]),
+        ];
+
+        for ((min_bytes, max_bytes), expected) in tests {
+            let is_match =
+                super::message_query_matcher(&query(min_bytes, max_bytes), &message_options)
+                    .unwrap();
+            let selected: Vec<usize> = sizes
+                .iter()
+                .copied()
+                .filter(|size| is_match(&delivery(*size), 0))
+                .collect();
+            assert_eq!(selected, expected, "{:?} {:?}", min_bytes, max_bytes);
+        }
+
+        let err = super::message_query_matcher(&query(Some(10), Some(9)), &message_options)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<crate::ReplayError>(),
+            Some(crate::ReplayError::InvalidSizeRange(10, 9))
+        ));
+
+        let message = super::to_message(delivery(1024), 7, &message_options, false).unwrap();
+        assert_eq!(message.size_bytes, 1024);
+        assert_eq!(message.data.len(), 1024);
+        assert_eq!(message.encoding, super::PayloadEncoding::Utf8);
+    }
+
+    #[tokio::test]
+    async fn test_body_filter() {
+        let bodies: [&[u8]; 5] = [
+            br#"{"order":{"id":"order-42","total":42,"paid":true}}"#,
+            br#"{"order":{"id":"order-7","total":7,"paid":false}}"#,
+            br#"{"order":{"id":"42","total":"42"}}"#,
+            b"order-42 shipped",
+            b"\xff\xfeorder-42",
+        ];
+        let test_cases = vec![
+            ((Some("order-42"), None, None), vec![0, 3]),
+            ((Some("order"), None, None), vec![0, 1, 2, 3]),
+            ((Some("missing"), None, None), vec![]),
+            ((None, Some("/order/id"), Some("order-42")), vec![0]),
+            ((None, Some("/order/id"), Some(r#""order-42""#)), vec![0]),
+            //a value that is valid JSON is compared as JSON
+            ((None, Some("/order/total"), Some("42")), vec![0]),
+            ((None, Some("/order/total"), Some(r#""42""#)), vec![2]),
+            ((None, Some("/order/id"), Some("42")), vec![]),
+            ((None, Some("/order/paid"), Some("false")), vec![1]),
+            ((None, Some("/order/missing"), Some("null")), vec![]),
+            (
+                (
+                    None,
+                    Some(""),
+                    Some(r#"{"order":{"id":"42","total":"42"}}"#),
+                ),
+                vec![2],
+            ),
+            //both have to match
+            ((Some("paid"), Some("/order/total"), Some("7")), vec![1]),
+            ((Some("shipped"), Some("/order/total"), Some("42")), vec![]),
+        ];
+        for ((contains, json_pointer, json_eq), expected) in test_cases {
+            let filter = super::body_filter(contains, json_pointer, json_eq)
+                .unwrap()
+                .unwrap();
+            let matched: Vec<usize> = (0..bodies.len())
+                .filter(|i| filter.matches(bodies[*i]))
+                .collect();
+            assert_eq!(
+                matched, expected,
+                "{:?} {:?} {:?}",
+                contains, json_pointer, json_eq
+            );
+        }
+
+        assert!(super::body_filter(None, None, None).unwrap().is_none());
+        let invalid = vec![
+            (Some("order/id"), Some("42")),
+            (Some("/order/id"), None),
+            (None, Some("42")),
+        ];
+        for (json_pointer, json_eq) in invalid {
+            assert!(matches!(
+                super::body_filter(None, json_pointer, json_eq),
+                Err(crate::ReplayError::InvalidBodyFilter(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_header() {
+        let mut meta = FieldTable::default();
+        meta.insert(
+            ShortString::from("tenant"),
+            AMQPValue::LongString("acme".into()),
+        );
+        meta.insert(
+            ShortString::from("region"),
+            AMQPValue::LongString("eu".into()),
+        );
+        let mut headers = FieldTable::default();
+        headers.insert(ShortString::from("meta"), AMQPValue::FieldTable(meta));
+        headers.insert(
+            ShortString::from("x-stream-transaction-id"),
+            AMQPValue::LongString("transaction_1".into()),
+        );
+        headers.insert(
+            ShortString::from("app.version"),
+            AMQPValue::LongString("1.2.3".into()),
+        );
+
+        let tests = vec![
+            ("x-stream-transaction-id", Some("transaction_1")),
+            ("meta.tenant", Some("acme")),
+            ("meta.region", Some("eu")),
+            ("meta.missing", None),
+            ("meta.tenant.deeper", None),
+            ("x-stream-transaction-id.nested", None),
+            ("missing.tenant", None),
+            ("app\\.version", Some("1.2.3")),
+            ("app.version", None),
+            ("", None),
+        ];
+
+        for (name, expected) in tests {
+            let value = match super::lookup_header(&headers, name) {
+                Some(AMQPValue::LongString(value)) => Some(value.to_string()),
+                _ => None,
+            };
+            assert_eq!(expected.map(String::from), value, "{}", name);
+        }
+
+        assert!(matches!(
+            super::lookup_header(&headers, "meta"),
+            Some(AMQPValue::FieldTable(_))
+        ));
+    }
 
     #[tokio::test]
     async fn test_is_within_timeframe() {